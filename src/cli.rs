@@ -1,16 +1,42 @@
 use crate::errors::Context;
+use crate::image::Image;
+use crate::journal::mount_images;
 use crate::journal::Change;
 use crate::journal::ChangeFilter;
+use crate::journal::DiscardMode;
 use crate::journal::Journal;
+use crate::journal::Replayer;
+use crate::report;
+use crate::report::CaseReport;
+use crate::report::Outcome;
+use crate::tree::CrashModel;
+use crate::tree::TreeChange;
+use crate::tree::TreeJournal;
 use log::info;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
 use rand::Rng;
+use rand::SeedableRng;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::io;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Instant;
 use structopt::StructOpt;
 use tempfile::tempdir;
 
@@ -25,6 +51,10 @@ struct PathOpt {
     #[structopt(short, long)]
     #[structopt(default_value = "./changes")]
     changes: PathBuf,
+
+    /// Treat the "base" as a directory and record file-level changes
+    #[structopt(long)]
+    posix: bool,
 }
 
 #[derive(Debug, Default, StructOpt)]
@@ -34,14 +64,39 @@ struct FilterOpt {
     /// For example, "24:01011" means take the first 24 writes,
     /// then skip the 25th (0), take 26th (1), skip 27th (0),
     /// take 28th and 29th write operations.
+    ///
+    /// Shorter forms: "0-23,25,27-28" lists (0-based) indexes or ranges of
+    /// changes to take. "^24,26" takes everything except the 25th and 27th.
+    /// "rle:24,1,1,1,2" lists lengths of alternating runs of taken and
+    /// skipped changes. Lists of indexes need a "," or "-": "10" is read as
+    /// bits, use "10," to take the 11th change only.
+    ///
+    /// Changes listed after "/" are applied last, as if they were
+    /// reordered. For example, "24:01011/25" applies the 26th write after
+    /// the 28th and 29th.
+    ///
+    /// A "@N" suffix crashes at change N: changes since the (N+1)-th are not
+    /// taken. For example, "24:01011@27" takes the 26th but not the 28th and
+    /// 29th writes.
     #[structopt(short, long)]
     #[structopt(default_value = "")]
     filter: String,
+
+    #[structopt(flatten)]
+    replay: ReplayOpt,
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+struct ReplayOpt {
+    /// How discarded ranges read back: "zero", "keep" (old data) or "garbage"
+    #[structopt(long)]
+    #[structopt(default_value = "zero")]
+    discard: DiscardMode,
 }
 
 #[derive(Debug, StructOpt)]
 struct MutateOpt {
-    /// Discard Sync operations
+    /// Discard Sync operations (fsync, fdatasync, flush, release)
     #[structopt(long)]
     drop_sync: bool,
 
@@ -52,6 +107,39 @@ struct MutateOpt {
     /// Insert Write operations with zeros
     #[structopt(long)]
     zero_fill: bool,
+
+    /// Split writes at sector boundaries so they can be torn
+    #[structopt(long, conflicts_with = "split-write")]
+    torn_write: bool,
+
+    /// Sector size, the atomic unit for --torn-write and --misdirected-write
+    #[structopt(long)]
+    #[structopt(default_value = "512")]
+    sector_size: usize,
+
+    /// Also split sectors into halves for --torn-write, so a sector can be
+    /// half old and half new
+    #[structopt(long)]
+    half_sector: bool,
+
+    /// Flip a random bit in the given number of random writes
+    #[structopt(long)]
+    #[structopt(default_value = "0")]
+    bit_rot: usize,
+
+    /// Move the given number of random writes by a few sectors
+    #[structopt(long)]
+    #[structopt(default_value = "0")]
+    misdirected_write: usize,
+
+    /// Drop the given number of random writes, even if they are synced
+    #[structopt(long)]
+    #[structopt(default_value = "0")]
+    lost_write: usize,
+
+    /// Seed for random mutations. Printed if not set
+    #[structopt(long)]
+    seed: Option<u64>,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -61,12 +149,160 @@ struct RunOpt {
     sudo: bool,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+struct SuiteOpt {
+    /// Run all test cases even if some fail, and print a summary
+    #[structopt(long)]
+    keep_going: bool,
+
+    /// Pass images to the verify script as regular files instead of
+    /// mounting them with FUSE. Changes made by the verify script are
+    /// discarded
+    #[structopt(long)]
+    no_fuse: bool,
+
+    /// Number of test cases to verify in parallel
+    #[structopt(short, long)]
+    #[structopt(default_value = "1")]
+    jobs: usize,
+
+    /// Only show output of the verify script for failed test cases.
+    /// Output of all cases is kept in the "results" directory
+    #[structopt(long)]
+    failures_only: bool,
+
+    /// Write results to the given path in the JUnit XML format
+    #[structopt(long)]
+    junit: Option<PathBuf>,
+
+    /// Write results to the given path in the TAP format
+    #[structopt(long)]
+    tap: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, StructOpt)]
 struct GenTestsOpt {
     /// Log(Maximum test cases generated between 2 Syncs) / Log(2)
     #[structopt(short, long)]
     #[structopt(default_value = "8")]
     max_cases_log2: usize,
+
+    /// Do not treat fdatasync as a barrier like fsync
+    #[structopt(long)]
+    no_datasync_barrier: bool,
+
+    /// Treat flush (on close) and release as barriers like fsync
+    #[structopt(long)]
+    flush_barrier: bool,
+
+    /// Also generate cases where a write lands after a later overlapping
+    /// write that was in flight before the same barrier
+    #[structopt(long)]
+    reorder: bool,
+
+    /// Model a volatile write cache: skip cases that only differ in writes
+    /// completely overwritten by later writes, as they are identical on disk
+    #[structopt(long)]
+    cache_model: bool,
+
+    /// Do not merge cases that produce identical data
+    #[structopt(long)]
+    no_dedup: bool,
+
+    /// Seed for random sampling. Printed if not set
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Crash consistency model in --posix mode: "ext4-ordered" or "anything-goes"
+    #[structopt(long)]
+    #[structopt(default_value = "ext4-ordered")]
+    model: CrashModel,
+
+    /// How to sample cases if there are too many: "random", "prefix",
+    /// "prefix-plus-one", "subset" or "coverage". Only "random" is
+    /// supported in --posix mode
+    #[structopt(long)]
+    #[structopt(default_value = "random")]
+    strategy: Strategy,
+
+    /// Maximum number of dropped writes for the "subset" strategy
+    #[structopt(long)]
+    #[structopt(default_value = "2")]
+    subset_size: usize,
+
+    /// Where to crash: "barriers" (before each barrier and at the end), or
+    /// "all" (before every change, and at the end)
+    #[structopt(long)]
+    #[structopt(default_value = "barriers")]
+    crash_points: CrashPoints,
+}
+
+/// Strategies to sample test cases between 2 barriers.
+#[derive(Debug, Clone, Copy)]
+enum Strategy {
+    /// Random bit flips.
+    Random,
+
+    /// The first k writes are taken, the rest are dropped.
+    Prefix,
+
+    /// A prefix, plus a later write that was reordered before the rest.
+    PrefixPlusOne,
+
+    /// Up to `subset_size` writes are dropped.
+    Subset,
+
+    /// Every write appears both taken and dropped.
+    Coverage,
+}
+
+/// Positions to crash at.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CrashPoints {
+    Barriers,
+    All,
+}
+
+/// A generated test case.
+#[derive(Serialize)]
+struct TestCase {
+    /// Filter of changes to take.
+    filter: String,
+
+    /// How the case was generated, for example, "exhaustive" or "prefix".
+    strategy: &'static str,
+
+    /// Range of changes the case varies, ending at the crash point.
+    window: (usize, usize),
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct FormatOpt {
+    /// Output format: "text", or "json" (one JSON object per line)
+    #[structopt(long)]
+    #[structopt(default_value = "text")]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown format: {}", s),
+            )),
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -98,6 +334,31 @@ struct MountOpt {
     dest: PathBuf,
 }
 
+#[derive(Debug, StructOpt)]
+struct NbdOpt {
+    #[structopt(flatten)]
+    paths: PathOpt,
+
+    #[structopt(flatten)]
+    filter: FilterOpt,
+
+    #[structopt(flatten)]
+    run: RunOpt,
+
+    /// Whether to record changes back to disk.
+    #[structopt(short, long)]
+    record: bool,
+
+    /// Shell command to run with the socket path as $1
+    #[structopt(short, long)]
+    exec: Option<String>,
+
+    /// Path to the unix socket to listen on
+    #[structopt(short, long)]
+    #[structopt(default_value = "./nbd.sock")]
+    socket: PathBuf,
+}
+
 #[derive(Debug, StructOpt)]
 enum Opt {
     /// Mounts image and record changes
@@ -109,6 +370,18 @@ enum Opt {
         opts: MountOpt,
     },
 
+    /// Serves image over NBD and record changes
+    ///
+    /// Unlike mount, flush, FUA and trim requests from the block layer are
+    /// recorded directly. Use `nbd-client -unix` or qemu to connect.
+    ///
+    /// Without --exec, the process will wait for ENTER in stdin before stopping.
+    /// With --exec, the process will stop after executing the command.
+    Nbd {
+        #[structopt(flatten)]
+        opts: NbdOpt,
+    },
+
     /// Merges changes into base image
     Merge {
         #[structopt(flatten)]
@@ -135,6 +408,9 @@ enum Opt {
         /// Show detailed bytes
         #[structopt(short, long)]
         verbose: bool,
+
+        #[structopt(flatten)]
+        format: FormatOpt,
     },
 
     /// Generate "filter"s for testing
@@ -144,6 +420,12 @@ enum Opt {
 
         #[structopt(flatten)]
         test: GenTestsOpt,
+
+        #[structopt(flatten)]
+        replay: ReplayOpt,
+
+        #[structopt(flatten)]
+        format: FormatOpt,
     },
 
     /// Run a test suite script
@@ -162,19 +444,76 @@ enum Opt {
     ///
     /// The input and output files are created in a temporary directory
     /// that will be deleted unless `--keep` is set.
+    ///
+    /// With `--posix`, `prepare` creates a directory instead of an image,
+    /// and the directory is mounted directly without root.
     RunSuite {
         /// Script to run
         script_path: PathBuf,
 
-        /// Whether to keep the temporary directory
+        /// Whether to keep the temporary directory, including
+        /// output of the verify script in "results"
         #[structopt(short, long)]
         keep: bool,
 
+        /// Record file-level changes of a directory
+        #[structopt(long)]
+        posix: bool,
+
         #[structopt(flatten)]
         run: RunOpt,
 
         #[structopt(flatten)]
         test: GenTestsOpt,
+
+        #[structopt(flatten)]
+        replay: ReplayOpt,
+
+        #[structopt(flatten)]
+        format: FormatOpt,
+
+        #[structopt(flatten)]
+        suite: SuiteOpt,
+    },
+
+    /// Writes the image with a filter applied to a regular file
+    ///
+    /// In multi-file or --posix mode, the output is a directory.
+    Materialize {
+        #[structopt(flatten)]
+        paths: PathOpt,
+
+        #[structopt(flatten)]
+        filter: FilterOpt,
+
+        /// Output path. An existing file or directory at the path is
+        /// replaced
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+
+    /// Minimize a failing filter
+    ///
+    /// Find a minimal set of changes to drop, so the verify script of a
+    /// test suite still fails with the same exit code. Run it in a
+    /// directory kept by `run-suite --keep`.
+    Minimize {
+        /// Script to run, as for run-suite
+        script_path: PathBuf,
+
+        #[structopt(flatten)]
+        paths: PathOpt,
+
+        #[structopt(flatten)]
+        filter: FilterOpt,
+
+        #[structopt(flatten)]
+        run: RunOpt,
+
+        /// Pass images to the verify script as regular files instead of
+        /// mounting them with FUSE, as for run-suite
+        #[structopt(long)]
+        no_fuse: bool,
     },
 }
 
@@ -197,23 +536,83 @@ fn save_journal(journal: &Journal, opt: &PathOpt) -> io::Result<()> {
     Ok(())
 }
 
-fn mutate_journal(journal: &mut Journal, opt: &MutateOpt) {
+fn load_tree_journal(opt: &PathOpt) -> io::Result<TreeJournal> {
+    info!(
+        "reading tree at {} with changes {}",
+        opt.base.display(),
+        opt.changes.display()
+    );
+    TreeJournal::load(&opt.base, &opt.changes)
+}
+
+fn save_tree_journal(journal: &TreeJournal, opt: &PathOpt) -> io::Result<()> {
+    info!("writing changes {}", opt.changes.display());
+    journal.dump(&opt.changes)?;
+    Ok(())
+}
+
+fn unsupported_in_posix_mode(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported in --posix mode", name),
+    )
+}
+
+/// Split `data` written at `offset` at offsets that are multiples of `unit`.
+fn split_at_boundaries(offset: usize, data: &[u8], unit: usize) -> Vec<(usize, &[u8])> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let next_boundary = ((offset + start) / unit + 1) * unit;
+        let end = (next_boundary - offset).min(data.len());
+        result.push((offset + start, &data[start..end]));
+        start = end;
+    }
+    result
+}
+
+fn mutate_journal(journal: &mut Journal, opt: &MutateOpt) -> io::Result<()> {
+    let uses_sectors = opt.torn_write || opt.misdirected_write > 0;
+    if uses_sectors && (opt.sector_size == 0 || (opt.half_sector && opt.sector_size < 2)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid sector size: {}", opt.sector_size),
+        ));
+    }
     let mut new_changes = Vec::new();
     for change in &journal.changes {
+        let (file, change) = change.split_file();
+        let mut push = |change: Change| new_changes.push(change.with_file(file));
         match change {
-            Change::Sync => {
+            Change::Sync | Change::DataSync | Change::Flush | Change::Release => {
                 if !opt.drop_sync {
-                    new_changes.push(Change::Sync);
+                    push(change.clone());
                 }
             }
-            Change::Write { offset, data } => {
+            Change::Discard { .. } | Change::Resize { .. } => push(change.clone()),
+            Change::File { .. } => unreachable!("nested File change"),
+            Change::Write { offset, data } | Change::FuaWrite { offset, data } => {
+                let fua = matches!(change, Change::FuaWrite { .. });
+                let new_write = |offset: usize, data: Vec<u8>| {
+                    if fua {
+                        Change::FuaWrite { offset, data }
+                    } else {
+                        Change::Write { offset, data }
+                    }
+                };
                 if opt.zero_fill && data.iter().any(|b| *b != 0) {
-                    new_changes.push(Change::Write {
-                        offset: *offset,
-                        data: vec![0; data.len()],
-                    });
+                    push(new_write(*offset, vec![0; data.len()]));
                 }
-                if opt.split_write && data.len() > 2048 {
+                if opt.torn_write {
+                    let unit = if opt.half_sector {
+                        opt.sector_size / 2
+                    } else {
+                        opt.sector_size
+                    };
+                    for (offset, sub) in split_at_boundaries(*offset, data, unit) {
+                        push(new_write(offset, sub.to_vec()));
+                    }
+                } else if opt.split_write && data.len() > 2048 {
                     let mut data_offset = 0;
                     while let Some(sub) =
                         data.get(data_offset..(data_offset + 2048).min(data.len()))
@@ -221,19 +620,108 @@ fn mutate_journal(journal: &mut Journal, opt: &MutateOpt) {
                         if sub.is_empty() {
                             break;
                         }
-                        new_changes.push(Change::Write {
-                            offset: offset + data_offset,
-                            data: sub.to_vec(),
-                        });
+                        push(new_write(offset + data_offset, sub.to_vec()));
                         data_offset += sub.len();
                     }
                 } else {
-                    new_changes.push(change.clone());
+                    push(change.clone());
                 }
             }
         }
     }
+    if opt.bit_rot > 0 || opt.misdirected_write > 0 || opt.lost_write > 0 {
+        let lens: Vec<usize> = if journal.is_multi_file() {
+            journal.files.iter().map(|(_, data)| data.len()).collect()
+        } else {
+            vec![journal.initial_data.len()]
+        };
+        inject_faults(&mut new_changes, &lens, opt);
+    }
     journal.changes = new_changes;
+    Ok(())
+}
+
+/// Corrupt, move, or drop random writes.
+///
+/// `lens` are initial lengths of files. Misdirected writes stay within the
+/// file, so they do not change its length.
+fn inject_faults(changes: &mut Vec<Change>, lens: &[usize], opt: &MutateOpt) {
+    let seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    if opt.seed.is_none() {
+        eprintln!("mutate seed: {}", seed);
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let writes: Vec<usize> = (0..changes.len())
+        .filter(|&i| changes[i].as_write().map(|(_, d)| !d.is_empty()) == Some(true))
+        .collect();
+    let picked = writes.choose_multiple(
+        &mut rng,
+        opt.bit_rot + opt.misdirected_write + opt.lost_write,
+    );
+    let mut lost = Vec::new();
+    for (n, &i) in picked.enumerate() {
+        let (file, change) = changes[i].split_file();
+        let (mut offset, mut data) = match change.as_write() {
+            Some((offset, data)) => (offset, data.to_vec()),
+            None => continue,
+        };
+        if n < opt.bit_rot {
+            let pos = rng.gen_range(0, data.len());
+            data[pos] ^= 1 << rng.gen_range(0, 8);
+            info!("bit rot: change {} at byte {}", i, offset + pos);
+        } else if n < opt.bit_rot + opt.misdirected_write {
+            // Move by 1 to 8 sectors, within the file.
+            let len = file_len_before(changes, i, file, lens[file.unwrap_or(0)]);
+            let end = len.max(offset + data.len());
+            let mut offsets = Vec::new();
+            for distance in (1..9).map(|k| k * opt.sector_size) {
+                if offset >= distance {
+                    offsets.push(offset - distance);
+                }
+                if offset + distance + data.len() <= end {
+                    offsets.push(offset + distance);
+                }
+            }
+            offset = match offsets.choose(&mut rng) {
+                Some(&offset) => offset,
+                None => {
+                    info!("misdirected write: change {} cannot move", i);
+                    continue;
+                }
+            };
+            info!("misdirected write: change {} to offset {}", i, offset);
+        } else {
+            info!("lost write: change {}", i);
+            lost.push(i);
+            continue;
+        }
+        let new_change = match change {
+            Change::FuaWrite { .. } => Change::FuaWrite { offset, data },
+            _ => Change::Write { offset, data },
+        };
+        changes[i] = new_change.with_file(file);
+    }
+    lost.sort_unstable();
+    for i in lost.into_iter().rev() {
+        changes.remove(i);
+    }
+}
+
+/// Length of a file before the `end`-th change, if its initial length is
+/// `len`.
+fn file_len_before(changes: &[Change], end: usize, file: Option<usize>, mut len: usize) -> usize {
+    for change in &changes[..end] {
+        let (change_file, change) = change.split_file();
+        if change_file != file {
+            continue;
+        }
+        if let Change::Resize { len: new_len } = change {
+            len = *new_len;
+        } else if let Some((offset, data)) = change.as_write() {
+            len = len.max(offset + data.len());
+        }
+    }
+    len
 }
 
 fn parse_filter(opt: &FilterOpt) -> io::Result<Option<ChangeFilter>> {
@@ -244,114 +732,840 @@ fn parse_filter(opt: &FilterOpt) -> io::Result<Option<ChangeFilter>> {
     }
 }
 
-fn show_changes(changes: &[Change], verbose: bool) {
+/// Hash of written data, to tell whether 2 writes have the same content.
+fn content_hash(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn show_changes(journal: &Journal, verbose: bool, format: Format) {
+    let changes = &journal.changes;
     if changes.is_empty() {
         info!("No changes");
     }
     for (i, change) in changes.iter().enumerate() {
+        if format == Format::Json {
+            println!("{}", change_to_json(journal, i, change, verbose));
+            continue;
+        }
         print!("{:6} ", i);
+        let (file, change) = change.split_file();
+        if let Some(file) = file {
+            print!("{}: ", journal.files[file].0);
+        }
         match change {
             Change::Sync => println!("Sync"),
-            Change::Write { offset, data } => {
+            Change::DataSync => println!("DataSync"),
+            Change::Flush => println!("Flush"),
+            Change::Release => println!("Release"),
+            Change::Discard { offset, len } => println!("Discard at {} with {} bytes", offset, len),
+            Change::Resize { len } => println!("Resize to {} bytes", len),
+            Change::Write { offset, data } | Change::FuaWrite { offset, data } => {
+                let name = match change {
+                    Change::FuaWrite { .. } => "FuaWrite",
+                    _ => "Write",
+                };
                 if verbose {
-                    println!("Write at {} with {:?}", offset, data);
+                    println!("{} at {} with {:?}", name, offset, data);
                 } else {
                     let is_zero = data.iter().all(|b| *b == 0);
                     println!(
-                        "Write at {} with {} bytes{}",
+                        "{} at {} with {} bytes{}",
+                        name,
                         offset,
                         data.len(),
                         if is_zero { " of zeros" } else { "" }
                     );
                 }
             }
+            Change::File { .. } => unreachable!("nested File change"),
         }
     }
 }
 
-fn gen_tests(mut changes: Vec<Change>, opt: &GenTestsOpt) -> Vec<String> {
-    let max_width: usize = opt.max_cases_log2;
-    let mut result = Vec::new();
+/// Describe a change as a JSON object.
+fn change_to_json(journal: &Journal, index: usize, change: &Change, verbose: bool) -> Value {
+    let (file, change) = change.split_file();
+    let mut value = json!({ "index": index, "kind": change.kind() });
+    if let Some(file) = file {
+        value["file"] = json!(journal.files[file].0);
+    }
+    match change {
+        Change::Write { offset, data } | Change::FuaWrite { offset, data } => {
+            value["offset"] = json!(offset);
+            value["length"] = json!(data.len());
+            value["zero"] = json!(data.iter().all(|b| *b == 0));
+            value["hash"] = json!(content_hash(data));
+            if verbose {
+                value["data"] = json!(data);
+            }
+        }
+        Change::Discard { offset, len } => {
+            value["offset"] = json!(offset);
+            value["length"] = json!(len);
+        }
+        Change::Resize { len } => value["length"] = json!(len),
+        _ => {}
+    }
+    value
+}
 
-    // Ensure the last change is Sync.
-    if let Some(Change::Write { .. }) = changes.last() {
-        changes.push(Change::Sync);
+fn show_tree_changes(journal: &TreeJournal, verbose: bool, format: Format) {
+    let changes = &journal.changes;
+    if changes.is_empty() {
+        info!("No changes");
     }
-    // Figure out locations of "Sync"s.
-    let mut sync_indexes = Vec::new();
     for (i, change) in changes.iter().enumerate() {
-        if let Change::Sync = change {
-            sync_indexes.push(i);
+        if format == Format::Json {
+            println!("{}", tree_change_to_json(i, change, verbose));
+            continue;
         }
-    }
-    // For each "Sync", generate test cases.
-    for (i, sync_index) in sync_indexes.iter().enumerate() {
-        // start_index .. sync_index
-        let start_index = if i == 0 { 0 } else { sync_indexes[i - 1] + 1 };
-        let width = sync_index - start_index;
-        if width == 0 {
-            // Ignore - no writes.
-        } else if width <= max_width {
-            info!(
-                "# All cases for {} writes before #{} Sync",
-                width, sync_index,
-            );
-            for bits in 0..(1 << width) {
-                result.push(format!("{}:{:0width$b}", start_index, bits, width = width));
+        print!("{:6} ", i);
+        match change {
+            TreeChange::Create { parent, name, mode } => {
+                println!("Create {:?} in {} with mode {:o}", name, parent, mode)
             }
-        } else {
-            let n = 1 << max_width;
-            info!(
-                "# Random {} cases for {} writes before #{} Sync",
-                n, width, sync_index,
-            );
-            let mut bits = vec![false; width];
-            let mut rng = rand::thread_rng();
-            let mut visited: HashSet<String> = HashSet::new();
-            while visited.len() < n {
-                // Do a few bit flips.
-                let bit_flip_count = rng.gen_range(1, width * 2 / max_width);
-                for _ in 0..bit_flip_count {
-                    let idx = rng.gen_range(0, width);
-                    bits[idx] = !bits[idx];
-                }
-                let bits_str: String = bits
-                    .iter()
-                    .map(|&b| if b { "1" } else { "0" })
-                    .collect::<Vec<&str>>()
-                    .concat();
-                if visited.insert(bits_str.clone()) {
-                    result.push(format!("{}:{}", start_index, bits_str));
+            TreeChange::Mkdir { parent, name, mode } => {
+                println!("Mkdir {:?} in {} with mode {:o}", name, parent, mode)
+            }
+            TreeChange::Symlink {
+                parent,
+                name,
+                target,
+            } => println!("Symlink {:?} in {} to {:?}", name, parent, target),
+            TreeChange::Link { ino, parent, name } => {
+                println!("Link {} as {:?} in {}", ino, name, parent)
+            }
+            TreeChange::Unlink { parent, name } => println!("Unlink {:?} in {}", name, parent),
+            TreeChange::Rmdir { parent, name } => println!("Rmdir {:?} in {}", name, parent),
+            TreeChange::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+            } => println!(
+                "Rename {:?} in {} to {:?} in {}",
+                name, parent, new_name, new_parent
+            ),
+            TreeChange::SetLen { ino, len } => println!("SetLen {} to {} bytes", ino, len),
+            TreeChange::Write { ino, offset, data } => {
+                if verbose {
+                    println!("Write {} at {} with {:?}", ino, offset, data);
+                } else {
+                    println!("Write {} at {} with {} bytes", ino, offset, data.len());
                 }
             }
+            TreeChange::Fsync { ino } => println!("Fsync {}", ino),
+            TreeChange::FsyncDir { ino } => println!("FsyncDir {}", ino),
+            TreeChange::Chmod { ino, mode } => println!("Chmod {} to {:o}", ino, mode),
         }
     }
+}
 
-    result
+/// Describe a file-level change as a JSON object.
+///
+/// Fields of the change are kept as-is, except that written data is
+/// summarized by length, zero-ness and hash unless `verbose` is set.
+fn tree_change_to_json(index: usize, change: &TreeChange, verbose: bool) -> Value {
+    let mut value = json!({ "index": index });
+    // Serialized as {"Kind": {fields}}.
+    if let Ok(Value::Object(map)) = serde_json::to_value(change) {
+        for (kind, fields) in map {
+            value["kind"] = json!(kind);
+            if let Value::Object(fields) = fields {
+                for (name, field) in fields {
+                    value[name] = field;
+                }
+            }
+        }
+    }
+    if let TreeChange::Write { data, .. } = change {
+        value["length"] = json!(data.len());
+        value["zero"] = json!(data.iter().all(|b| *b == 0));
+        value["hash"] = json!(content_hash(data));
+        if !verbose {
+            value.as_object_mut().unwrap().remove("data");
+        }
+    }
+    value
 }
 
-fn wait_stdin() {
-    let stdin = io::stdin();
-    let mut s = String::new();
-    let _ = stdin.read_line(&mut s);
+/// Test if a change is treated as a barrier when generating tests.
+///
+/// Writes before a barrier are persisted if the barrier is taken.
+fn is_barrier(change: &Change, opt: &GenTestsOpt) -> bool {
+    match change {
+        Change::Sync => true,
+        Change::DataSync => !opt.no_datasync_barrier,
+        Change::Flush | Change::Release => opt.flush_barrier,
+        Change::Write { .. }
+        | Change::FuaWrite { .. }
+        | Change::Discard { .. }
+        | Change::Resize { .. } => false,
+        Change::File { change, .. } => is_barrier(change, opt),
+    }
 }
 
-fn execute(mut args: Vec<String>, run: &RunOpt) -> io::Result<ExitStatus> {
-    if run.sudo && unsafe { libc::getuid() } != 0 {
-        let mut sudo_path = None;
-        for path in &["/usr/bin/sudo", "/run/wrappers/bin/sudo"] {
-            if Path::new(path).exists() {
-                sudo_path = Some(path.to_string());
+/// Find changes that can be skipped when crashing before `end`.
+///
+/// A change is persisted if a later barrier to the same file is taken. Only
+/// writes, discards and resizes can be skipped. Other changes, including FUA
+/// writes, are always taken.
+///
+/// Return `(start, indexes)`. Changes before `start` are always taken.
+fn find_skippable(
+    changes: &[Change],
+    end: usize,
+    files: &HashSet<Option<usize>>,
+    opt: &GenTestsOpt,
+) -> (usize, Vec<usize>) {
+    let mut synced_files = HashSet::new();
+    let mut start = 0;
+    let mut indexes = Vec::new();
+    for j in (0..end).rev() {
+        let (file, change) = changes[j].split_file();
+        if is_barrier(change, opt) {
+            synced_files.insert(file);
+            if synced_files.len() >= files.len() {
+                start = j + 1;
+                break;
             }
+        } else if !synced_files.contains(&file)
+            && matches!(
+                change,
+                Change::Write { .. } | Change::Discard { .. } | Change::Resize { .. }
+            )
+        {
+            indexes.push(j);
         }
-        match sudo_path {
-            Some(path) => args.insert(0, path),
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "can not find sudo in common locations",
-                ))
-            }
+    }
+    indexes.reverse();
+    (start, indexes)
+}
+
+/// Test if two changes are writes to overlapping ranges of the same file.
+fn is_overlapping(a: &Change, b: &Change) -> bool {
+    match (a.as_write(), b.as_write()) {
+        (Some((a_offset, a_data)), Some((b_offset, b_data))) => {
+            a.split_file().0 == b.split_file().0
+                && a_offset < b_offset + b_data.len()
+                && b_offset < a_offset + a_data.len()
+        }
+        _ => false,
+    }
+}
+
+/// Orders of in-flight writes that land after the other changes before `end`.
+///
+/// Writes in `indexes` are in flight until the barrier at `end`, so the disk
+/// can persist them in any order. The barriers recorded in the journal bound
+/// the window, so the order FUSE passed the writes in is not needed.
+///
+/// Writes are grouped by overlapping ranges, and each group is permuted on its
+/// own, since writes in different groups do not affect each other. Writes that
+/// are not in flight, like FUA writes, keep their positions. Writes followed by
+/// an overlapping discard, or a resize cutting them, are not moved. Orders that keep all
+/// overlapping writes in their original order are left out.
+///
+/// Return up to `limit` orders. Each lists writes of a group to apply last.
+fn reorder_cases(
+    changes: &[Change],
+    start: usize,
+    end: usize,
+    indexes: &[usize],
+    limit: usize,
+    rng: &mut StdRng,
+) -> Vec<Vec<usize>> {
+    let movable = indexes.iter().cloned().filter(|&a| {
+        let file = changes[a].split_file().0;
+        let (a_start, a_end) = match changes[a].as_write() {
+            Some((offset, data)) => (offset, offset + data.len()),
+            None => return false,
+        };
+        !((a + 1)..end).any(|b| match changes[b].split_file() {
+            (f, Change::Discard { offset, len }) => {
+                f == file && *offset < a_end && offset + len > a_start
+            }
+            (f, Change::Resize { len }) => f == file && *len < a_end,
+            _ => false,
+        })
+    });
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for a in movable {
+        let (joined, mut rest): (Vec<_>, Vec<_>) = groups.into_iter().partition(|group| {
+            group
+                .iter()
+                .any(|&b| is_overlapping(&changes[a], &changes[b]))
+        });
+        let mut group: Vec<usize> = joined.into_iter().flatten().collect();
+        group.push(a);
+        group.sort_unstable();
+        rest.push(group);
+        groups = rest;
+    }
+    groups.sort();
+
+    let mut result = Vec::new();
+    for group in groups {
+        // Writes that keep their positions, but are overlapped by the group.
+        let fixed: Vec<usize> = (start..end)
+            .filter(|b| {
+                !group.contains(b)
+                    && group
+                        .iter()
+                        .any(|&a| is_overlapping(&changes[a], &changes[*b]))
+            })
+            .collect();
+        let passes_fixed = group.iter().any(|&a| {
+            fixed
+                .iter()
+                .any(|&b| a < b && is_overlapping(&changes[a], &changes[b]))
+        });
+        let reorders = |order: &[usize]| -> bool {
+            passes_fixed
+                || (0..order.len()).any(|i| {
+                    order[i + 1..]
+                        .iter()
+                        .any(|&b| order[i] > b && is_overlapping(&changes[order[i]], &changes[b]))
+                })
+        };
+        let budget = limit.saturating_sub(result.len());
+        let permutations = (1..=group.len()).try_fold(1usize, |n, k| n.checked_mul(k));
+        if permutations.map(|n| n <= budget) == Some(true) {
+            // Enumerate all permutations in lexicographical order.
+            let mut order = group.clone();
+            loop {
+                if reorders(&order) {
+                    result.push(order.clone());
+                }
+                match (1..order.len()).rev().find(|&i| order[i - 1] < order[i]) {
+                    Some(i) => {
+                        let j = (i..order.len()).rev().find(|&j| order[j] > order[i - 1]);
+                        order.swap(i - 1, j.unwrap());
+                        order[i..].reverse();
+                    }
+                    None => break,
+                }
+            }
+        } else {
+            let mut visited = HashSet::new();
+            for _ in 0..budget * 4 {
+                if visited.len() >= budget {
+                    break;
+                }
+                let mut order = group.clone();
+                order.shuffle(rng);
+                if reorders(&order) && visited.insert(order.clone()) {
+                    result.push(order);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Mark skippable changes that are overwritten by later taken writes as
+/// skipped, so cases producing the same on-disk state become identical.
+///
+/// `indexes` are skippable changes in `start..end`, `bits` tells whether they
+/// are taken. Other changes in the range are taken.
+fn canonicalize(
+    changes: &[Change],
+    start: usize,
+    end: usize,
+    indexes: &[usize],
+    bits: &mut [bool],
+) {
+    // Ranges written by taken changes after the current one, per file.
+    let mut written: HashMap<Option<usize>, Vec<(usize, usize)>> = HashMap::new();
+    let mut k = indexes.len();
+    for j in (start..end).rev() {
+        let (file, change) = changes[j].split_file();
+        let range = match change {
+            Change::Write { offset, data } | Change::FuaWrite { offset, data } => {
+                Some((*offset, offset + data.len()))
+            }
+            Change::Discard { offset, len } => Some((*offset, offset + len)),
+            _ => None,
+        };
+        let ranges = written.entry(file).or_default();
+        let taken = if k > 0 && indexes[k - 1] == j {
+            k -= 1;
+            if let Some(range) = range {
+                if is_covered(ranges, range) {
+                    bits[k] = false;
+                }
+            }
+            bits[k]
+        } else {
+            true
+        };
+        // Discarded ranges do not count. They might read back as old data.
+        if let (true, Some(range), Some(_)) = (taken, range, change.as_write()) {
+            ranges.push(range);
+        }
+    }
+}
+
+/// Test if `range` is covered by `ranges`.
+fn is_covered(ranges: &[(usize, usize)], range: (usize, usize)) -> bool {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_unstable();
+    let mut pos = range.0;
+    for (start, end) in sorted {
+        if pos >= range.1 {
+            break;
+        }
+        if start <= pos {
+            pos = pos.max(end);
+        }
+    }
+    pos >= range.1
+}
+
+/// Remove cases that produce the same data as previously seen cases.
+///
+/// All `cases` take changes before `start`. An image is hashed as the sum of
+/// hashes of pages that differ from the initial image. The hash of the
+/// checkpoint before `start` is updated with pages changed since the
+/// checkpoint, so the cost of a case is proportional to the changes it
+/// replays. Images with the same hash are compared.
+fn dedup_cases(
+    replayer: &mut Replayer,
+    start: usize,
+    cases: Vec<TestCase>,
+    seen: &mut HashMap<u64, Vec<Vec<Image>>>,
+) -> Vec<TestCase> {
+    let (_, initial) = replayer.checkpoint(0);
+    let (_, checkpoint) = replayer.checkpoint(start);
+    let checkpoint_hashes: Vec<u64> = checkpoint
+        .iter()
+        .zip(&initial)
+        .map(|(image, initial)| {
+            let pages = image.changed_pages(initial);
+            image_hash(image, initial, &pages, 0)
+        })
+        .collect();
+    let mut result = Vec::new();
+    for case in cases {
+        let filter: ChangeFilter = case.filter.parse().expect("generated filter is valid");
+        let images = replayer.images(Some(&filter));
+        let mut hasher = DefaultHasher::new();
+        for (i, image) in images.iter().enumerate() {
+            let pages = image.changed_pages(&checkpoint[i]);
+            let old_hash = image_hash(&checkpoint[i], &initial[i], &pages, 0);
+            let hash = image_hash(image, &initial[i], &pages, checkpoint_hashes[i]);
+            hasher.write_u64(hash.wrapping_sub(old_hash));
+        }
+        let same_hash = seen.entry(hasher.finish()).or_default();
+        if !same_hash.contains(&images) {
+            same_hash.push(images);
+            result.push(case);
+        }
+    }
+    result
+}
+
+/// Add hashes of the length and `pages` of `image` that differ from
+/// `initial` to `hash`.
+fn image_hash(image: &Image, initial: &Image, pages: &[usize], hash: u64) -> u64 {
+    let mut hash = hash.wrapping_add(image.len() as u64);
+    for &index in pages {
+        let page = image.page(index);
+        if page != initial.page(index) {
+            let mut hasher = DefaultHasher::new();
+            (index, page).hash(&mut hasher);
+            hash = hash.wrapping_add(hasher.finish());
+        }
+    }
+    hash
+}
+
+/// Generate tests for the journal at `paths`.
+///
+/// Return the seed used for random sampling, and the tests.
+fn gen_tests_at(
+    paths: &PathOpt,
+    opt: &GenTestsOpt,
+    replay: &ReplayOpt,
+) -> io::Result<(u64, Vec<TestCase>)> {
+    let seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("gen-tests seed: {}", seed);
+    let tests = if paths.posix {
+        if let Some(name) = posix_unsupported_option(opt) {
+            return Err(unsupported_in_posix_mode(name));
+        }
+        load_tree_journal(paths)?
+            .gen_tests(opt.model, opt.max_cases_log2, seed)
+            .into_iter()
+            .map(|(filter, strategy)| {
+                // Cases are in the "start:bits" form.
+                let window = match filter.find(':') {
+                    Some(pos) => {
+                        let start = filter[..pos].parse().unwrap_or(0);
+                        (start, start + filter.len() - pos - 1)
+                    }
+                    None => (0, 0),
+                };
+                TestCase {
+                    filter,
+                    strategy,
+                    window,
+                }
+            })
+            .collect()
+    } else {
+        let mut journal = load_journal(paths)?;
+        journal.discard_mode = replay.discard;
+        gen_tests(&journal, opt, seed)
+    };
+    Ok((seed, tests))
+}
+
+/// Find a gen-tests option set to a value that --posix mode does not support,
+/// so it does not silently generate other cases than requested.
+fn posix_unsupported_option(opt: &GenTestsOpt) -> Option<&'static str> {
+    let options = [
+        ("--no-datasync-barrier", opt.no_datasync_barrier),
+        ("--flush-barrier", opt.flush_barrier),
+        ("--reorder", opt.reorder),
+        ("--cache-model", opt.cache_model),
+        ("--no-dedup", opt.no_dedup),
+        ("--strategy", !matches!(opt.strategy, Strategy::Random)),
+        ("--crash-points", opt.crash_points != CrashPoints::Barriers),
+    ];
+    options.iter().find(|(_, set)| *set).map(|(name, _)| *name)
+}
+
+fn gen_tests(journal: &Journal, opt: &GenTestsOpt, seed: u64) -> Vec<TestCase> {
+    let changes = &journal.changes;
+    let max_width: usize = opt.max_cases_log2;
+    let mut result = Vec::new();
+    let mut seen = HashMap::new();
+    let mut total = 0;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut replayer = Replayer::new(journal);
+
+    // Figure out locations of barriers.
+    let mut sync_indexes = Vec::new();
+    if opt.crash_points == CrashPoints::All {
+        // Crash before every change, including the first one, and at the end.
+        sync_indexes.extend(0..=changes.len());
+    } else {
+        for (i, change) in changes.iter().enumerate() {
+            if is_barrier(change, opt) {
+                sync_indexes.push(i);
+            }
+        }
+        // Ensure there is a barrier at the end.
+        if let Some(change) = changes.last() {
+            if !is_barrier(change, opt) {
+                sync_indexes.push(changes.len());
+            }
+        }
+    }
+    let files: HashSet<Option<usize>> = changes.iter().map(|c| c.split_file().0).collect();
+    // For each barrier, generate test cases.
+    for sync_index in sync_indexes.iter() {
+        // start_index .. sync_index
+        let (start_index, write_indexes) = find_skippable(changes, *sync_index, &files, opt);
+        let width = write_indexes.len();
+        let mut cases = Vec::new();
+        let to_filter = |bits: &[bool]| -> String {
+            let mut bits_str = vec!["1"; sync_index - start_index];
+            for (&j, &b) in write_indexes.iter().zip(bits) {
+                bits_str[j - start_index] = if b { "1" } else { "0" };
+            }
+            if opt.crash_points == CrashPoints::All {
+                format!("{}:{}@{}", start_index, bits_str.concat(), sync_index)
+            } else {
+                format!("{}:{}", start_index, bits_str.concat())
+            }
+        };
+        if width == 0 && opt.crash_points == CrashPoints::Barriers {
+            // Ignore - no writes. Crashing at other changes still produces
+            // a case with all changes taken.
+        } else if width <= max_width {
+            info!(
+                "# All cases for {} writes before #{} Sync",
+                width, sync_index,
+            );
+            let mut visited: HashSet<Vec<bool>> = HashSet::new();
+            for n in 0..(1 << width) {
+                let mut bits: Vec<bool> = (0..width)
+                    .map(|k| (n >> (width - 1 - k)) & 1 == 1)
+                    .collect();
+                if opt.cache_model {
+                    canonicalize(changes, start_index, *sync_index, &write_indexes, &mut bits);
+                }
+                if visited.insert(bits.clone()) {
+                    cases.push(TestCase {
+                        filter: to_filter(&bits),
+                        strategy: "exhaustive",
+                        window: (start_index, *sync_index),
+                    });
+                }
+            }
+        } else {
+            let n = 1 << max_width;
+            info!(
+                "# Sample up to {} cases ({}) for {} writes before #{} Sync",
+                n,
+                opt.strategy.name(),
+                width,
+                sync_index,
+            );
+            let mut visited: HashSet<Vec<bool>> = HashSet::new();
+            for mut bits in sample_cases(width, n, opt, &mut rng) {
+                if opt.cache_model {
+                    canonicalize(changes, start_index, *sync_index, &write_indexes, &mut bits);
+                }
+                if visited.insert(bits.clone()) {
+                    cases.push(TestCase {
+                        filter: to_filter(&bits),
+                        strategy: opt.strategy.name(),
+                        window: (start_index, *sync_index),
+                    });
+                }
+            }
+        }
+        if opt.reorder {
+            let orders = reorder_cases(
+                changes,
+                start_index,
+                *sync_index,
+                &write_indexes,
+                1 << max_width,
+                &mut rng,
+            );
+            let all_taken = to_filter(&vec![true; width]);
+            let reordered: Vec<TestCase> = orders
+                .into_iter()
+                .map(|order| {
+                    let order: Vec<String> = order.iter().map(|i| i.to_string()).collect();
+                    TestCase {
+                        filter: format!("{}/{}", all_taken, order.join(",")),
+                        strategy: "reorder",
+                        window: (start_index, *sync_index),
+                    }
+                })
+                .collect();
+            if !reordered.is_empty() {
+                info!(
+                    "# {} reordered cases before #{} Sync",
+                    reordered.len(),
+                    sync_index
+                );
+            }
+            cases.extend(reordered);
+        }
+        total += cases.len();
+        if opt.no_dedup {
+            result.extend(cases);
+        } else {
+            result.extend(dedup_cases(&mut replayer, start_index, cases, &mut seen));
+        }
+    }
+    if !opt.no_dedup {
+        eprintln!(
+            "{} of {} test cases collapsed as they produce identical data",
+            total - result.len(),
+            total
+        );
+    }
+
+    result
+}
+
+impl Strategy {
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::Random => "random",
+            Strategy::Prefix => "prefix",
+            Strategy::PrefixPlusOne => "prefix-plus-one",
+            Strategy::Subset => "subset",
+            Strategy::Coverage => "coverage",
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "prefix" => Ok(Strategy::Prefix),
+            "prefix-plus-one" => Ok(Strategy::PrefixPlusOne),
+            "subset" => Ok(Strategy::Subset),
+            "coverage" => Ok(Strategy::Coverage),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown strategy: {}", s),
+            )),
+        }
+    }
+}
+
+impl FromStr for CrashPoints {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "barriers" => Ok(CrashPoints::Barriers),
+            "all" => Ok(CrashPoints::All),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown crash points: {}", s),
+            )),
+        }
+    }
+}
+
+/// Number of ways to choose `k` items from `n` items, saturated.
+fn binomial(n: usize, k: usize) -> usize {
+    if k > n {
+        return 0;
+    }
+    let mut result: usize = 1;
+    for i in 0..k.min(n - k) {
+        // result * (n - i) is divisible by (i + 1).
+        result = match result.checked_mul(n - i) {
+            Some(product) => product / (i + 1),
+            None => return usize::MAX,
+        };
+    }
+    result
+}
+
+/// Sample up to `n` cases for `width` skippable writes.
+///
+/// Each case is a list of bits telling whether a write is taken.
+fn sample_cases(width: usize, n: usize, opt: &GenTestsOpt, rng: &mut StdRng) -> Vec<Vec<bool>> {
+    let max_width = opt.max_cases_log2;
+    let mut result = Vec::new();
+    match opt.strategy {
+        Strategy::Random => {
+            let mut bits = vec![false; width];
+            let mut visited: HashSet<Vec<bool>> = HashSet::new();
+            while visited.len() < n {
+                // Do a few bit flips.
+                let bit_flip_count = rng.gen_range(1, width * 2 / max_width);
+                for _ in 0..bit_flip_count {
+                    let idx = rng.gen_range(0, width);
+                    bits[idx] = !bits[idx];
+                }
+                if visited.insert(bits.clone()) {
+                    result.push(bits.clone());
+                }
+            }
+        }
+        Strategy::Prefix => {
+            // All prefixes, or evenly spaced ones if there are more than `n`.
+            let count = (width + 1).min(n);
+            for j in 0..count {
+                let k = if count == width + 1 {
+                    j
+                } else {
+                    j * width / (count - 1).max(1)
+                };
+                result.push((0..width).map(|i| i < k).collect());
+            }
+        }
+        Strategy::PrefixPlusOne => {
+            let to_bits =
+                |k: usize, j: usize| -> Vec<bool> { (0..width).map(|i| i < k || i == j).collect() };
+            if binomial(width, 2) <= n {
+                for k in 0..width {
+                    for j in (k + 1)..width {
+                        result.push(to_bits(k, j));
+                    }
+                }
+            } else {
+                let mut visited = HashSet::new();
+                while visited.len() < n {
+                    let k = rng.gen_range(0, width - 1);
+                    let j = rng.gen_range(k + 1, width);
+                    if visited.insert((k, j)) {
+                        result.push(to_bits(k, j));
+                    }
+                }
+            }
+        }
+        Strategy::Subset => {
+            let to_bits = |dropped: &[usize]| -> Vec<bool> {
+                (0..width).map(|i| !dropped.contains(&i)).collect()
+            };
+            for size in 1..=opt.subset_size.min(width) {
+                let budget = n.saturating_sub(result.len());
+                if binomial(width, size) <= budget {
+                    // Enumerate all combinations in lexicographical order.
+                    let mut dropped: Vec<usize> = (0..size).collect();
+                    loop {
+                        result.push(to_bits(&dropped));
+                        match (0..size).rev().find(|&i| dropped[i] < width - size + i) {
+                            Some(i) => {
+                                dropped[i] += 1;
+                                for j in (i + 1)..size {
+                                    dropped[j] = dropped[j - 1] + 1;
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                } else {
+                    let mut visited = HashSet::new();
+                    while visited.len() < budget {
+                        let mut dropped = rand::seq::index::sample(rng, width, size).into_vec();
+                        dropped.sort_unstable();
+                        if visited.insert(dropped.clone()) {
+                            result.push(to_bits(&dropped));
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        Strategy::Coverage => {
+            // Writes not yet seen as taken (false) or dropped (true).
+            let mut uncovered: Vec<(usize, bool)> = (0..width)
+                .flat_map(|i| vec![(i, true), (i, false)])
+                .collect();
+            while !uncovered.is_empty() && result.len() < n {
+                let mut bits: Vec<bool> = (0..width).map(|_| rng.gen()).collect();
+                let (i, taken) = uncovered[0];
+                bits[i] = taken;
+                uncovered.retain(|&(i, taken)| bits[i] != taken);
+                result.push(bits);
+            }
+        }
+    }
+    result
+}
+
+fn wait_stdin() {
+    let stdin = io::stdin();
+    let mut s = String::new();
+    let _ = stdin.read_line(&mut s);
+}
+
+fn execute(mut args: Vec<String>, run: &RunOpt) -> io::Result<ExitStatus> {
+    if run.sudo && unsafe { libc::getuid() } != 0 {
+        let mut sudo_path = None;
+        for path in &["/usr/bin/sudo", "/run/wrappers/bin/sudo"] {
+            if Path::new(path).exists() {
+                sudo_path = Some(path.to_string());
+            }
+        }
+        match sudo_path {
+            Some(path) => args.insert(0, path),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "can not find sudo in common locations",
+                ))
+            }
         }
     }
     info!("running: {}", shell_words::join(&args[..]));
@@ -361,6 +1575,33 @@ fn execute(mut args: Vec<String>, run: &RunOpt) -> io::Result<ExitStatus> {
         .context("run script")
 }
 
+/// Run `exec` with the mount (or socket) path, or wait for ENTER if `exec` is
+/// not set.
+fn run_mounted(exec: Option<String>, dest: &Path, run: &RunOpt) -> io::Result<i32> {
+    let mut result = 0;
+    match exec {
+        Some(cmd) => {
+            let sh_args = vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                cmd,
+                "--".to_string(),
+                dest.display().to_string(),
+            ];
+            let status = execute(sh_args, run)?;
+            if let Some(code) = status.code() {
+                result = code;
+                info!("child exited with {}", code);
+            }
+        }
+        None => {
+            info!("press ENTER to write changes and unmount");
+            wait_stdin();
+        }
+    }
+    Ok(result)
+}
+
 fn mount(opts: MountOpt) -> io::Result<i32> {
     let MountOpt {
         paths,
@@ -372,99 +1613,664 @@ fn mount(opts: MountOpt) -> io::Result<i32> {
         record,
     } = opts;
 
-    let mut result = 0;
+    if paths.posix {
+        let mut journal = load_tree_journal(&paths)?;
+        let filter = parse_filter(&filter)?;
+        let _ = fs::create_dir_all(&dest);
+        let session = journal
+            .mount(&dest, &fuse_args, filter.as_ref())
+            .context(format!("mounting outagefs to {}", dest.display()))?;
+        info!("mounted: {}", dest.display());
+        let result = run_mounted(exec, &dest, &run)?;
+        drop(session);
+        info!("unmounted: {}", dest.display());
+        if record {
+            save_tree_journal(&journal, &paths)?;
+            info!("changes written: {}", paths.changes.display());
+        }
+        return Ok(result);
+    }
+
     let mut journal = load_journal(&paths)?;
-    let filter = parse_filter(&filter)?;
-    // Create the file if it does not exist.
-    let _ = fs::OpenOptions::new().write(true).create(true).open(&dest);
-    let session = journal
-        .mount(&dest, &fuse_args, filter.as_ref())
+    journal.discard_mode = filter.replay.discard;
+    let images = journal.images(parse_filter(&filter)?.as_ref());
+    let names = journal.file_names();
+    let opts = MountOpt {
+        paths,
+        fuse_args,
+        dest,
+        filter,
+        exec,
+        run,
+        record,
+    };
+    let result = run_images(names, images, &mut journal.changes, &opts, false)?;
+    if record {
+        save_journal(&journal, &opts.paths)?;
+        info!("changes written: {}", opts.paths.changes.display());
+    }
+    Ok(result)
+}
+
+/// Write the image with the filter applied to `dest`.
+///
+/// In multi-file or POSIX mode, `dest` is a directory.
+fn materialize(paths: &PathOpt, filter: &FilterOpt, dest: &Path) -> io::Result<()> {
+    if paths.posix {
+        let journal = load_tree_journal(paths)?;
+        let filter = parse_filter(filter)?;
+        return journal.tree(filter.as_ref()).save(dest);
+    }
+
+    let mut journal = load_journal(paths)?;
+    journal.discard_mode = filter.replay.discard;
+    let filter = parse_filter(filter)?;
+    let images = journal.images(filter.as_ref());
+    write_images(&journal.file_names(), &images, dest)
+}
+
+/// Write images to `dest` as a single file, or as a directory of files with
+/// the given names if `names` is not empty.
+fn write_images(names: &[String], images: &[Image], dest: &Path) -> io::Result<()> {
+    if names.is_empty() {
+        let mut file = fs::File::create(dest).context(dest.display())?;
+        images[0].write_to(&mut file).context(dest.display())?;
+    } else {
+        fs::create_dir_all(dest).context(dest.display())?;
+        for (name, image) in names.iter().zip(images) {
+            let path = dest.join(name);
+            let mut file = fs::File::create(&path).context(path.display())?;
+            image.write_to(&mut file).context(path.display())?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the file or directory at `dest` left by the last run.
+fn remove_dest(dest: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dest) {
+        Ok(metadata) if metadata.is_dir() => {
+            // Materialized trees can have read-only directories.
+            make_dirs_writable(dest)?;
+            fs::remove_dir_all(dest).context(dest.display())?;
+        }
+        Ok(_) => fs::remove_file(dest).context(dest.display())?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// Make `dir` and directories in it writable by the owner.
+fn make_dirs_writable(dir: &Path) -> io::Result<()> {
+    let mode = fs::metadata(dir)
+        .context(dir.display())?
+        .permissions()
+        .mode();
+    if mode & 0o700 != 0o700 {
+        let permissions = fs::Permissions::from_mode(mode | 0o700);
+        fs::set_permissions(dir, permissions).context(dir.display())?;
+    }
+    for entry in fs::read_dir(dir).context(dir.display())? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_dirs_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Like `mount`, but pass the image as regular files without FUSE.
+///
+/// Changes cannot be recorded.
+fn run_materialized(opts: MountOpt) -> io::Result<i32> {
+    let MountOpt {
+        paths,
+        dest,
+        filter,
+        exec,
+        run,
+        ..
+    } = opts;
+
+    remove_dest(&dest)?;
+    materialize(&paths, &filter, &dest)?;
+    info!("materialized: {}", dest.display());
+    run_mounted(exec, &dest, &run)
+}
+
+/// Mount images replayed by the caller, or write them as regular files if
+/// `no_fuse` is set, and run the command in `opts`.
+///
+/// Changes to the mounted files are recorded in `changes`.
+fn run_images(
+    names: Vec<String>,
+    images: Vec<Image>,
+    changes: &mut Vec<Change>,
+    opts: &MountOpt,
+    no_fuse: bool,
+) -> io::Result<i32> {
+    let MountOpt {
+        fuse_args,
+        dest,
+        exec,
+        run,
+        ..
+    } = opts;
+
+    if no_fuse {
+        remove_dest(dest)?;
+        write_images(&names, &images, dest)?;
+        info!("materialized: {}", dest.display());
+        return run_mounted(exec.clone(), dest, run);
+    }
+
+    // Create the file (or directory in multi-file mode) if it does not exist.
+    if names.is_empty() {
+        let _ = fs::OpenOptions::new().write(true).create(true).open(dest);
+    } else {
+        let _ = fs::create_dir_all(dest);
+    }
+    let session = mount_images(names, images, changes, dest, fuse_args)
         .context(format!("mounting outagefs to {}", dest.display()))?;
     info!("mounted: {}", dest.display());
-    match exec {
-        Some(cmd) => {
-            let sh_args = vec![
-                "/bin/sh".to_string(),
-                "-c".to_string(),
-                cmd.clone(),
-                "--".to_string(),
+    let result = run_mounted(exec.clone(), dest, run)?;
+    drop(session);
+    info!("unmounted: {}", dest.display());
+    Ok(result)
+}
+
+fn nbd(opts: NbdOpt) -> io::Result<i32> {
+    let NbdOpt {
+        paths,
+        filter,
+        run,
+        record,
+        exec,
+        socket,
+    } = opts;
+
+    if paths.posix {
+        return Err(unsupported_in_posix_mode("nbd"));
+    }
+    let mut journal = load_journal(&paths)?;
+    journal.discard_mode = filter.replay.discard;
+    let filter = parse_filter(&filter)?;
+    let server = journal
+        .serve_nbd(&socket, filter.as_ref())
+        .context(format!("serving nbd at {}", socket.display()))?;
+    info!("serving: {}", socket.display());
+    let result = run_mounted(exec, &socket, &run)?;
+    drop(server);
+    info!("stopped: {}", socket.display());
+    if record {
+        save_journal(&journal, &paths)?;
+        info!("changes written: {}", paths.changes.display());
+    }
+    Ok(result)
+}
+
+fn run_script(
+    script_path: &str,
+    run: &RunOpt,
+    test: &GenTestsOpt,
+    replay: &ReplayOpt,
+    posix: bool,
+    format: Format,
+    suite: &SuiteOpt,
+) -> io::Result<i32> {
+    // In JSON format, stdout only has JSON records. Output of the script
+    // goes to stderr.
+    let redirect = if format == Format::Json { " >&2" } else { "" };
+
+    // Prepare
+    let paths = PathOpt {
+        base: "base".into(),
+        changes: "changes".into(),
+        posix,
+    };
+    let prepare = shell_words::join(vec![
+        script_path.to_string(),
+        "prepare".into(),
+        paths.base.display().to_string(),
+    ]);
+    execute(
+        vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            format!("{}{}", prepare, redirect),
+        ],
+        &run,
+    )
+    .context("executing prepare script")?;
+
+    // Record changes
+    let dest = Path::new("mountpoint").to_path_buf();
+    mount(MountOpt {
+        paths: paths.clone(),
+        filter: FilterOpt::default(),
+        fuse_args: Vec::new(),
+        run: run.clone(),
+        record: true,
+        exec: Some(format!(
+            "{}{}",
+            shell_words::join(vec![
+                script_path.to_string(),
+                "changes".to_string(),
                 dest.display().to_string(),
-            ];
-            let status = execute(sh_args, &run)?;
-            if let Some(code) = status.code() {
-                result = code;
-                info!("child exited with {}", code);
+            ]),
+            redirect
+        )),
+        dest: dest.clone(),
+    })
+    .context("runing mount subcommand to record changes")?;
+
+    // Tests
+    let (seed, tests) = gen_tests_at(&paths, test, replay)?;
+    fs::write("seed", format!("{}\n", seed)).context("writing seed")?;
+
+    // Replay test cases incrementally, instead of replaying all changes for
+    // each case. POSIX mode replays trees in `mount`.
+    let journal = if posix {
+        None
+    } else {
+        let mut journal = load_journal(&paths)?;
+        journal.discard_mode = replay.discard;
+        Some(journal)
+    };
+    let mut replayer = journal.as_ref().map(Replayer::new);
+    let names = journal.as_ref().map(|j| j.file_names()).unwrap_or_default();
+    let total = tests.len();
+    let mut tested = vec![Tested::Unknown; tests.len()];
+    let mut tested_count = 0;
+    let mut first_error = None;
+    let mut stopped = None;
+    let mut reports: Vec<CaseReport> = tests
+        .iter()
+        .map(|case| CaseReport::new(&case.filter, case.strategy))
+        .collect();
+
+    // Each job verifies in its own directory, since the verify script might
+    // use relative paths.
+    let jobs = suite.jobs.max(1);
+    let mut idle_dirs: Vec<PathBuf> = if jobs == 1 {
+        vec![PathBuf::from(".")]
+    } else {
+        (0..jobs)
+            .rev()
+            .map(|k| format!("job{}", k).into())
+            .collect()
+    };
+    let results_dir = std::env::current_dir()?.join("results");
+    let (sender, receiver) = mpsc::channel();
+    let mut running = 0;
+    let mut last_index = 0;
+    loop {
+        // Start test cases until all jobs are busy.
+        while stopped.is_none() && !idle_dirs.is_empty() {
+            let i = match pick_next_case(&tested, last_index) {
+                Some(i) => i,
+                None => break,
+            };
+            tested[i] = Tested::Running;
+            last_index = i;
+            running += 1;
+            eprintln!(
+                "[{} of {}] Test Case #{} ({})",
+                tested_count + running,
+                total,
+                i,
+                tests[i].strategy
+            );
+            let dir = idle_dirs.pop().unwrap();
+            let mut prepare = || -> io::Result<_> {
+                // Capture output of the verify script to results/<index>.
+                let result_dir = results_dir.join(i.to_string());
+                fs::create_dir_all(&result_dir).context("creating results directory")?;
+                fs::write(result_dir.join("filter"), format!("{}\n", tests[i].filter))
+                    .context("writing filter")?;
+                fs::create_dir_all(&dir).context("creating job directory")?;
+                let mut exec = verify_command(script_path, &dest, &result_dir);
+                if jobs > 1 {
+                    exec = format!(
+                        "cd {} && {}",
+                        shell_words::quote(&dir.display().to_string()),
+                        exec
+                    );
+                }
+                let filter = FilterOpt {
+                    filter: tests[i].filter.clone(),
+                    replay: replay.clone(),
+                };
+                let images = match replayer.as_mut() {
+                    Some(replayer) => Some(replayer.images(parse_filter(&filter)?.as_ref())),
+                    None => None,
+                };
+                Ok((exec, filter, images))
+            };
+            let (exec, filter, images) = match prepare() {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    // Do not return yet. Running jobs still use their mounts
+                    // and the temporary directory.
+                    idle_dirs.push(dir);
+                    running -= 1;
+                    tested[i] = Tested::Error;
+                    reports[i].outcome = Outcome::Error(e.to_string());
+                    stopped = Some(Err(e));
+                    break;
+                }
+            };
+            let names = names.clone();
+            let opts = MountOpt {
+                paths: paths.clone(),
+                filter,
+                fuse_args: Vec::new(),
+                run: run.clone(),
+                record: false,
+                exec: Some(exec),
+                dest: dir.join(&dest),
+            };
+            let context = format!("runing mount subcommand to verify {}", &tests[i].filter);
+            let sender = sender.clone();
+            let no_fuse = suite.no_fuse;
+            std::thread::spawn(move || {
+                let start_time = Instant::now();
+                // The result must be sent even if the job panics. Otherwise
+                // the main thread waits forever.
+                let result = catch_panic(|| match images {
+                    Some(images) => run_images(names, images, &mut Vec::new(), &opts, no_fuse),
+                    None if no_fuse => run_materialized(opts),
+                    None => mount(opts),
+                });
+                let result = result.context(context);
+                let _ = sender.send((i, dir, result, start_time.elapsed()));
+            });
+        }
+        if running == 0 {
+            break;
+        }
+
+        // Wait for a test case to complete.
+        let (i, dir, result, duration) = receiver.recv().unwrap();
+        idle_dirs.push(dir);
+        running -= 1;
+        tested_count += 1;
+        let result_dir = results_dir.join(i.to_string());
+        let report = &mut reports[i];
+        report.duration = duration;
+        let stdout = fs::read(result_dir.join("stdout")).unwrap_or_default();
+        let stderr = fs::read(result_dir.join("stderr")).unwrap_or_default();
+        report.stdout = String::from_utf8_lossy(&stdout).into_owned();
+        report.stderr = String::from_utf8_lossy(&stderr).into_owned();
+        if format == Format::Json {
+            let mut value = json!(tests[i]);
+            value["index"] = json!(i);
+            match &result {
+                Ok(code) => value["exit_code"] = json!(code),
+                Err(e) => value["error"] = json!(e.to_string()),
+            }
+            value["duration_ms"] = json!(report.duration.as_millis() as u64);
+            println!("{}", value);
+        }
+        tested[i] = match result {
+            Ok(code) => {
+                info!("verify script returned {}", code);
+                if code >= 10 && code < 20 {
+                    report.outcome = Outcome::Pass(code);
+                    Tested::Pass((code - 10) as _)
+                } else if code == 0 {
+                    report.outcome = Outcome::Pass(code);
+                    Tested::Pass(0)
+                } else {
+                    eprintln!(
+                        "verify script returned {} for filter {}",
+                        code, &tests[i].filter
+                    );
+                    report.outcome = Outcome::Fail(code);
+                    if !suite.keep_going && stopped.is_none() {
+                        stopped = Some(Ok(code));
+                    }
+                    Tested::Fail(code)
+                }
             }
+            Err(e) => {
+                report.outcome = Outcome::Error(e.to_string());
+                if suite.keep_going {
+                    eprintln!("{}", e);
+                    first_error.get_or_insert(e);
+                } else if stopped.is_none() {
+                    stopped = Some(Err(e));
+                } else {
+                    eprintln!("{}", e);
+                }
+                Tested::Error
+            }
+        };
+        if !suite.failures_only || !matches!(tested[i], Tested::Pass(_)) {
+            if format == Format::Json {
+                let _ = io::stderr().write_all(&stdout);
+            } else {
+                let _ = io::stdout().write_all(&stdout);
+            }
+            let _ = io::stderr().write_all(&stderr);
+        }
+    }
+    write_reports(script_path, suite, &reports)?;
+    if let Some(result) = stopped {
+        return result;
+    }
+
+    eprintln!("{} test cases verified", tested_count);
+    if !suite.keep_going {
+        return Ok(0);
+    }
+
+    show_summary(&tests, &tested);
+    let first_failure = tested.iter().find_map(|t| match t {
+        Tested::Fail(code) => Some(*code),
+        _ => None,
+    });
+    match (first_failure, first_error) {
+        (Some(code), _) => Ok(code),
+        (None, Some(e)) => Err(e),
+        (None, None) => Ok(0),
+    }
+}
+
+/// Find the next "interesting" test case to verify.
+///
+/// Start with the first and the last cases, then bisect between passing
+/// cases with different variants, then pick untested cases after `last`.
+/// Cases that are running split bisect ranges, so parallel jobs bisect
+/// different ranges.
+fn pick_next_case(tested: &[Tested], last: usize) -> Option<usize> {
+    let len = tested.len();
+    if len == 0 {
+        return None;
+    }
+    for &i in &[0, len - 1] {
+        if tested[i] == Tested::Unknown {
+            return Some(i);
         }
-        None => {
-            info!("press ENTER to write changes and unmount");
-            wait_stdin();
+    }
+
+    // Find a bisect range.
+    let mut best_range_start = 0;
+    let mut best_range_distance = 0;
+    let mut last_pass_start = 0;
+    let mut last_pass_variant = 0;
+    for j in 0..len {
+        if let Tested::Pass(v) = tested[j] {
+            if v != last_pass_variant {
+                // Split the range by cases that are not untested.
+                let mut start = last_pass_start;
+                let range = tested.iter().enumerate().take(j + 1);
+                for (k, t) in range.skip(last_pass_start + 1) {
+                    if k == j || *t != Tested::Unknown {
+                        if k - start > best_range_distance {
+                            best_range_distance = k - start;
+                            best_range_start = start;
+                        }
+                        start = k;
+                    }
+                }
+            }
+            last_pass_start = j;
+            last_pass_variant = v;
         }
     }
-    drop(session);
-    info!("unmounted: {}", dest.display());
-    if record {
-        save_journal(&journal, &paths)?;
-        info!("changes written: {}", paths.changes.display());
+    let best_range_end = best_range_start + best_range_distance;
+    let best_range_mid = (best_range_end + best_range_start) / 2;
+    if best_range_distance > 1 {
+        info!(
+            "bisect {}..{}: {}",
+            best_range_start, best_range_end, best_range_mid
+        );
+        return Some(best_range_mid);
     }
-    Ok(result)
-}
 
-fn run_script(script_path: &str, run: &RunOpt, test: &GenTestsOpt) -> io::Result<i32> {
-    // Prepare
-    let paths = PathOpt {
-        base: "base".into(),
-        changes: "changes".into(),
-    };
-    execute(
-        vec![
-            script_path.to_string(),
-            "prepare".into(),
-            paths.base.display().to_string(),
-        ],
-        &run,
-    )
-    .context("executing prepare script")?;
+    let next = ((last + 1)..len)
+        .chain(0..=last)
+        .find(|&j| tested[j] == Tested::Unknown);
+    if let Some(j) = next {
+        info!("picking next untested case: {}", j);
+    }
+    next
+}
 
-    // Record changes
-    let dest = Path::new("mountpoint").to_path_buf();
-    mount(MountOpt {
-        paths: paths.clone(),
-        filter: FilterOpt::default(),
-        fuse_args: Vec::new(),
-        run: run.clone(),
-        record: true,
-        exec: Some(shell_words::join(vec![
-            script_path.to_string(),
-            "changes".to_string(),
-            dest.display().to_string(),
-        ])),
-        dest: dest.clone(),
-    })
-    .context("runing mount subcommand to record changes")?;
+/// Write JUnit XML or TAP reports if requested.
+fn write_reports(script_path: &str, suite: &SuiteOpt, reports: &[CaseReport]) -> io::Result<()> {
+    if let Some(path) = &suite.junit {
+        let name = Path::new(script_path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        fs::write(path, report::to_junit(&name, reports)).context("writing JUnit report")?;
+    }
+    if let Some(path) = &suite.tap {
+        fs::write(path, report::to_tap(reports)).context("writing TAP report")?;
+    }
+    Ok(())
+}
 
-    // Tests
-    let journal = load_journal(&paths)?;
-    let tests = gen_tests(journal.changes, test);
-    let total = tests.len();
-    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-    #[repr(u8)]
-    enum Tested {
-        Unknown,
-        Pass(usize),
+/// Find a minimal subset of `items` that passes `test`, using the "ddmin"
+/// delta debugging algorithm. `items` is expected to pass `test`.
+///
+/// Results are cached, so `test` is called once for each subset.
+fn ddmin(mut items: Vec<usize>, mut test: impl FnMut(&[usize]) -> bool) -> Vec<usize> {
+    let mut tested: HashMap<Vec<usize>, bool> = HashMap::new();
+    let mut test = |subset: &[usize]| -> bool {
+        *tested
+            .entry(subset.to_vec())
+            .or_insert_with(|| test(subset))
+    };
+    if test(&[]) {
+        return Vec::new();
     }
-    let mut tested = vec![Tested::Unknown; tests.len()];
-    let mut tested_count = 0;
-    let mut next_test_index = 0;
-    while tested_count < tests.len() {
-        let i = next_test_index;
-        tested_count += 1;
-        assert_eq!(tested[i], Tested::Unknown);
-        eprintln!("[{} of {}] Test Case #{}", tested_count, total, i);
-        let code = mount(MountOpt {
+    let mut n = 2;
+    while items.len() >= 2 {
+        let len = items.len();
+        let chunks: Vec<Vec<usize>> = (0..n)
+            .map(|k| items[k * len / n..(k + 1) * len / n].to_vec())
+            .collect();
+        let mut reduced = false;
+        // Try each chunk.
+        for chunk in &chunks {
+            if test(chunk) {
+                items = chunk.clone();
+                n = 2;
+                reduced = true;
+                break;
+            }
+        }
+        // Try each complement. With 2 chunks, complements are chunks.
+        if !reduced && chunks.len() > 2 {
+            for i in 0..chunks.len() {
+                let complement: Vec<usize> = chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .flat_map(|(_, c)| c.iter().cloned())
+                    .collect();
+                if test(&complement) {
+                    items = complement;
+                    n = (n - 1).max(2);
+                    reduced = true;
+                    break;
+                }
+            }
+        }
+        if !reduced {
+            if n >= items.len() {
+                break;
+            }
+            n = (n * 2).min(items.len());
+        }
+    }
+    items
+}
+
+/// Minimize the failing filter by dropping as few changes as possible.
+///
+/// Verify cases at `dest`, which is mounted, or written as regular files if
+/// `no_fuse` is set. Changes after the crash point of `filter` are never
+/// taken, so the minimized filter crashes at the same point.
+///
+/// Return the minimized filter and indexes of dropped changes.
+fn minimize(
+    script_path: &str,
+    paths: &PathOpt,
+    filter: &FilterOpt,
+    run: &RunOpt,
+    no_fuse: bool,
+    dest: &Path,
+) -> io::Result<(String, Vec<usize>)> {
+    let len = if paths.posix {
+        load_tree_journal(paths)?.changes.len()
+    } else {
+        load_journal(paths)?.changes.len()
+    };
+    let change_filter = match parse_filter(filter)? {
+        Some(change_filter) => change_filter,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--filter is required",
+            ))
+        }
+    };
+    let end = change_filter.end().filter(|&end| end < len);
+    let dropped: Vec<usize> = (0..end.unwrap_or(len))
+        .filter(|&i| !change_filter.should_take(i))
+        .collect();
+    let to_filter = |dropped: &[usize]| -> String {
+        let mut result = format!(
+            "^{}",
+            dropped
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        if !change_filter.deferred().is_empty() {
+            let deferred: Vec<String> = change_filter
+                .deferred()
+                .iter()
+                .map(|i| i.to_string())
+                .collect();
+            result += &format!("/{}", deferred.join(","));
+        }
+        if let Some(end) = end {
+            result += &format!("@{}", end);
+        }
+        result
+    };
+    let verify = |filter_str: &str| -> io::Result<i32> {
+        let opts = MountOpt {
             paths: paths.clone(),
             filter: FilterOpt {
-                filter: tests[i].clone(),
+                filter: filter_str.to_string(),
+                replay: filter.replay.clone(),
             },
             fuse_args: Vec::new(),
             run: run.clone(),
@@ -474,71 +2280,125 @@ fn run_script(script_path: &str, run: &RunOpt, test: &GenTestsOpt) -> io::Result
                 "verify".into(),
                 dest.display().to_string(),
             ])),
-            dest: dest.clone(),
-        })
-        .context(format!("runing mount subcommand to verify {}", &tests[i]))?;
-        info!("verify script returned {}", code);
-        if code >= 10 && code < 20 {
-            tested[i] = Tested::Pass((code - 10) as _);
-        } else if code == 0 {
-            tested[i] = Tested::Pass(0);
+            dest: dest.to_path_buf(),
+        };
+        let code = if no_fuse {
+            run_materialized(opts)
         } else {
-            eprintln!("verify script returned {} for filter {}", code, &tests[i]);
-            return Ok(code);
-        }
-
-        if tested_count >= tests.len() {
-            break;
+            mount(opts)
         }
+        .context(format!("runing mount subcommand to verify {}", filter_str))?;
+        info!("verify script returned {} for filter {}", code, filter_str);
+        Ok(code)
+    };
 
-        // Find the next "interesting" test.
-        next_test_index = if i == 0 {
-            tests.len() - 1
-        } else {
-            // Find a bisect range.
-            let mut best_range_start = 0;
-            let mut best_range_distance = 0;
-            let mut last_pass_start = 0;
-            let mut last_pass_variant = 0;
-            for j in 0..tests.len() {
-                match tested[j] {
-                    Tested::Unknown => continue,
-                    Tested::Pass(v) => {
-                        if v != last_pass_variant && j - last_pass_start > best_range_distance {
-                            best_range_distance = j - last_pass_start;
-                            best_range_start = last_pass_start;
-                        }
-                        last_pass_start = j;
-                        last_pass_variant = v;
-                    }
-                }
-            }
-            let best_range_end = best_range_start + best_range_distance;
-            let best_range_mid = (best_range_end + best_range_start) / 2;
-            if best_range_distance > 1 {
-                info!(
-                    "bisect {}..{}: {}",
-                    best_range_start, best_range_end, best_range_mid
-                );
-                best_range_mid
-            } else {
-                let mut j = (i + 1) % tests.len();
-                let mut count = 0;
-                while tested[j] != Tested::Unknown {
-                    j += 1;
-                    count += 1;
-                    assert!(count <= tests.len());
-                    if j >= tests.len() {
-                        j = 0;
-                    }
-                }
-                info!("picking next untested case: {}", j);
-                j
+    let expected = verify(&filter.filter)?;
+    if expected == 0 || (expected >= 10 && expected < 20) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "verify script returned {} for filter {}, which is not a failure",
+                expected, &filter.filter
+            ),
+        ));
+    }
+    eprintln!(
+        "minimizing {} dropped changes with exit code {}",
+        dropped.len(),
+        expected
+    );
+    let minimal = ddmin(dropped, |subset| {
+        // An error, for example, failing to mount, does not reproduce the
+        // failure.
+        let result = match verify(&to_filter(subset)) {
+            Ok(code) => code == expected,
+            Err(e) => {
+                eprintln!("{}", e);
+                false
             }
         };
+        eprintln!(
+            "{} changes dropped: {}",
+            subset.len(),
+            if result { "fails" } else { "does not fail" }
+        );
+        result
+    });
+    Ok((to_filter(&minimal), minimal))
+}
+
+/// Result of verifying a test case.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum Tested {
+    Unknown,
+
+    /// The case is being verified.
+    Running,
+
+    /// The verify script returned 0 or 10 to 19. The variant is the exit
+    /// code minus 10.
+    Pass(usize),
+
+    /// The verify script returned other exit codes.
+    Fail(i32),
+
+    /// The case cannot be verified, for example, mount failed.
+    Error,
+}
+
+/// Run `f`, turning a panic into an error.
+fn catch_panic(f: impl FnOnce() -> io::Result<i32>) -> io::Result<i32> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::other("verify job panicked")),
     }
-    eprintln!("{} test cases verified", tested_count);
-    Ok(0)
+}
+
+/// Shell command running the verify script, with stdout and stderr captured
+/// to files in `result_dir`.
+fn verify_command(script_path: &str, dest: &Path, result_dir: &Path) -> String {
+    format!(
+        "{} >{} 2>{}",
+        shell_words::join(vec![
+            script_path.to_string(),
+            "verify".into(),
+            dest.display().to_string(),
+        ]),
+        shell_words::quote(&result_dir.join("stdout").display().to_string()),
+        shell_words::quote(&result_dir.join("stderr").display().to_string()),
+    )
+}
+
+/// Print a table mapping each result to filters of the test cases.
+fn show_summary(tests: &[TestCase], tested: &[Tested]) {
+    eprintln!("{:<12} {:>6}  Filters", "Result", "Cases");
+    for (name, filters) in summary_groups(tests, tested) {
+        eprintln!("{:<12} {:>6}  {}", name, filters.len(), filters.join(" "));
+    }
+}
+
+/// Group filters of the test cases by result, in the order of `Tested`.
+fn summary_groups<'a>(tests: &'a [TestCase], tested: &[Tested]) -> Vec<(String, Vec<&'a str>)> {
+    let mut groups: BTreeMap<Tested, Vec<&str>> = BTreeMap::new();
+    for (case, result) in tests.iter().zip(tested) {
+        let result = match result {
+            Tested::Running => Tested::Unknown,
+            _ => *result,
+        };
+        groups.entry(result).or_default().push(&case.filter);
+    }
+    groups
+        .into_iter()
+        .map(|(result, filters)| {
+            let name = match result {
+                Tested::Unknown | Tested::Running => "untested".to_string(),
+                Tested::Pass(variant) => format!("pass {}", variant),
+                Tested::Fail(code) => format!("fail {}", code),
+                Tested::Error => "error".to_string(),
+            };
+            (name, filters)
+        })
+        .collect()
 }
 
 pub(crate) fn main() -> io::Result<()> {
@@ -547,44 +2407,908 @@ pub(crate) fn main() -> io::Result<()> {
         Opt::Mount { opts } => {
             mount(opts)?;
         }
+        Opt::Nbd { opts } => {
+            nbd(opts)?;
+        }
         Opt::Merge { paths, filter } => {
-            let journal = load_journal(&paths)?;
+            if paths.posix {
+                return Err(unsupported_in_posix_mode("merge"));
+            }
+            let mut journal = load_journal(&paths)?;
+            journal.discard_mode = filter.replay.discard;
             let filter = parse_filter(&filter)?;
-            let data = journal.data(filter.as_ref());
-            let journal = Journal::new(data);
+            let journal = if journal.is_multi_file() {
+                let files = (0..journal.files.len())
+                    .map(|i| {
+                        (
+                            journal.files[i].0.clone(),
+                            journal.file_data(i, filter.as_ref()),
+                        )
+                    })
+                    .collect();
+                Journal::with_files(files)
+            } else {
+                Journal::new(journal.data(filter.as_ref()))
+            };
             save_journal(&journal, &paths)?;
         }
         Opt::Mutate { paths, mutate } => {
+            if paths.posix {
+                return Err(unsupported_in_posix_mode("mutate"));
+            }
             let mut journal = load_journal(&paths)?;
-            mutate_journal(&mut journal, &mutate);
+            mutate_journal(&mut journal, &mutate)?;
             save_journal(&journal, &paths)?;
         }
-        Opt::Show { paths, verbose } => {
-            let journal = load_journal(&paths)?;
-            show_changes(&journal.changes, verbose);
+        Opt::Show {
+            paths,
+            verbose,
+            format,
+        } => {
+            if paths.posix {
+                show_tree_changes(&load_tree_journal(&paths)?, verbose, format.format);
+            } else {
+                show_changes(&load_journal(&paths)?, verbose, format.format);
+            }
         }
-        Opt::GenTests { paths, test } => {
-            let journal = load_journal(&paths)?;
-            for s in gen_tests(journal.changes, &test) {
-                println!("{}", s);
+        Opt::GenTests {
+            paths,
+            test,
+            replay,
+            format,
+        } => {
+            let (_seed, tests) = gen_tests_at(&paths, &test, &replay)?;
+            for case in tests {
+                info!("{} case: {}", case.strategy, case.filter);
+                match format.format {
+                    Format::Text => println!("{}", case.filter),
+                    Format::Json => println!("{}", json!(case)),
+                }
             }
         }
         Opt::RunSuite {
             script_path,
             keep,
+            posix,
             run,
             test,
+            replay,
+            format,
+            suite,
         } => {
             let script_path = script_path.canonicalize()?.display().to_string();
+            // Report paths are relative to the current directory.
+            let cwd = std::env::current_dir()?;
+            let mut suite = suite;
+            suite.junit = suite.junit.map(|p| cwd.join(p));
+            suite.tap = suite.tap.map(|p| cwd.join(p));
             let tmpdir = tempdir()?;
             let dir = &tmpdir.path();
             info!("chdir: {}", dir.display());
             std::env::set_current_dir(dir)?;
-            let _code = run_script(&script_path, &run, &test)?;
+            let result = run_script(
+                &script_path,
+                &run,
+                &test,
+                &replay,
+                posix,
+                format.format,
+                &suite,
+            );
             if keep {
                 eprintln!("keep tmpdir: {}", tmpdir.into_path().display());
+            } else {
+                drop(tmpdir);
+            }
+            let code = result?;
+            if code != 0 {
+                // Exit with the code of the failed test case.
+                std::process::exit(code);
             }
         }
+        Opt::Materialize {
+            paths,
+            filter,
+            output,
+        } => {
+            // Files not in the image must not be left over.
+            remove_dest(&output)?;
+            materialize(&paths, &filter, &output)?;
+        }
+        Opt::Minimize {
+            script_path,
+            paths,
+            filter,
+            run,
+            no_fuse,
+        } => {
+            let script_path = script_path.canonicalize()?.display().to_string();
+            let (filter, dropped) = minimize(
+                &script_path,
+                &paths,
+                &filter,
+                &run,
+                no_fuse,
+                Path::new("mountpoint"),
+            )?;
+            let dropped: Vec<String> = dropped.iter().map(|i| i.to_string()).collect();
+            eprintln!("dropped changes: {}", dropped.join(" "));
+            println!("{}", filter);
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_tests_opt(args: &[&str]) -> GenTestsOpt {
+        GenTestsOpt::from_iter(std::iter::once("gen-tests").chain(args.iter().cloned()))
+    }
+
+    fn mutate_opt(args: &[&str]) -> MutateOpt {
+        MutateOpt::from_iter(std::iter::once("mutate").chain(args.iter().cloned()))
+    }
+
+    #[test]
+    fn test_is_barrier() {
+        let write = Change::Write {
+            offset: 0,
+            data: vec![1],
+        };
+        let in_file = |change: Change| Change::File {
+            file: 1,
+            change: Box::new(change),
+        };
+        for (args, barriers) in [
+            (vec![], vec![true, true, false, false]),
+            (
+                vec!["--no-datasync-barrier"],
+                vec![true, false, false, false],
+            ),
+            (vec!["--flush-barrier"], vec![true, true, true, true]),
+        ] {
+            let opt = gen_tests_opt(&args);
+            let changes = vec![
+                Change::Sync,
+                Change::DataSync,
+                Change::Flush,
+                Change::Release,
+            ];
+            for (change, barrier) in changes.into_iter().zip(barriers) {
+                assert_eq!(
+                    is_barrier(&change, &opt),
+                    barrier,
+                    "{:?} {:?}",
+                    args,
+                    change
+                );
+                let change = in_file(change);
+                assert_eq!(
+                    is_barrier(&change, &opt),
+                    barrier,
+                    "{:?} {:?}",
+                    args,
+                    change
+                );
+            }
+            assert!(!is_barrier(&write, &opt));
+            assert!(!is_barrier(&in_file(write.clone()), &opt));
+        }
+    }
+
+    #[test]
+    fn test_is_covered() {
+        assert!(is_covered(&[(0, 2), (2, 4)], (1, 4)));
+        assert!(is_covered(&[(5, 9), (0, 6)], (1, 8)));
+        assert!(!is_covered(&[(0, 2), (3, 4)], (0, 4)));
+        assert!(!is_covered(&[(1, 4)], (0, 2)));
+        assert!(!is_covered(&[], (0, 1)));
+        assert!(is_covered(&[], (3, 3)));
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let write = |offset: usize, len: usize| Change::Write {
+            offset,
+            data: vec![1; len],
+        };
+        let canonical = |changes: &[Change], indexes: &[usize], bits: &[bool]| {
+            let mut bits = bits.to_vec();
+            canonicalize(changes, 0, changes.len(), indexes, &mut bits);
+            bits
+        };
+
+        let changes = vec![write(0, 4), write(2, 4), write(0, 8)];
+        let indexes = [0, 1, 2];
+        // Both earlier writes are overwritten by the last one.
+        assert_eq!(
+            canonical(&changes, &indexes, &[true, true, true]),
+            [false, false, true]
+        );
+        // A skipped write does not overwrite anything.
+        assert_eq!(
+            canonical(&changes, &indexes, &[true, true, false]),
+            [true, true, false]
+        );
+        assert_eq!(
+            canonical(&changes, &indexes, &[true, false, false]),
+            [true, false, false]
+        );
+
+        // Writes that are not skippable are taken.
+        let changes = vec![write(0, 4), write(0, 4)];
+        assert_eq!(canonical(&changes, &[0], &[true]), [false]);
+
+        // Discards and writes to other files do not overwrite.
+        let changes = vec![
+            write(0, 4),
+            Change::Discard { offset: 0, len: 4 },
+            Change::File {
+                file: 1,
+                change: Box::new(write(0, 4)),
+            },
+        ];
+        assert_eq!(
+            canonical(&changes, &[0, 1, 2], &[true, true, true]),
+            [true, true, true]
+        );
+    }
+
+    #[test]
+    fn test_split_at_boundaries() {
+        let data: Vec<u8> = (0..10).collect();
+        assert_eq!(
+            split_at_boundaries(3, &data, 4),
+            vec![
+                (3, &data[..1]),
+                (4, &data[1..5]),
+                (8, &data[5..9]),
+                (12, &data[9..])
+            ]
+        );
+        assert_eq!(split_at_boundaries(4, &data[..4], 4), vec![(4, &data[..4])]);
+        assert_eq!(split_at_boundaries(0, &data, 512), vec![(0, &data[..])]);
+        assert!(split_at_boundaries(5, &[], 4).is_empty());
+
+        assert!(mutate_opt(&["--torn-write"]).torn_write);
+        let args = ["mutate", "--torn-write", "--split-write"];
+        assert!(MutateOpt::from_iter_safe(args.iter()).is_err());
+    }
+
+    #[test]
+    fn test_inject_faults() {
+        let changes: Vec<Change> = (0..8)
+            .map(|i| Change::Write {
+                offset: i * 512,
+                data: vec![i as u8; 512],
+            })
+            .collect();
+        let writes = |changes: &[Change]| -> Vec<(usize, Vec<u8>)> {
+            let writes = changes.iter().map(|c| c.as_write().unwrap());
+            writes
+                .map(|(offset, data)| (offset, data.to_vec()))
+                .collect()
+        };
+        for seed in 0..20 {
+            let seed = seed.to_string();
+            let inject = |args: &[&str]| -> Vec<(usize, Vec<u8>)> {
+                let mut changes = changes.clone();
+                let args = [args, &["--seed", &seed]].concat();
+                inject_faults(&mut changes, &[4096], &mutate_opt(&args));
+                writes(&changes)
+            };
+
+            // Bit rot flips one bit.
+            let rotten = inject(&["--bit-rot", "1"]);
+            let flipped: u32 = (rotten.iter().zip(writes(&changes)))
+                .flat_map(|((_, a), (_, b))| a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()))
+                .sum();
+            assert_eq!(flipped, 1);
+
+            // Lost writes are removed.
+            let lost = inject(&["--lost-write", "3"]);
+            assert_eq!(lost.len(), 5);
+            assert!(lost.iter().all(|w| writes(&changes).contains(w)));
+
+            // Misdirected writes move, but stay within the file.
+            let moved = inject(&["--misdirected-write", "8"]);
+            assert!(moved
+                .iter()
+                .all(|(offset, data)| offset + data.len() <= 4096));
+            assert!(moved
+                .iter()
+                .all(|(offset, data)| *offset != data[0] as usize * 512));
+        }
+
+        // Writes extending the file only move backward, if they can.
+        let opt = mutate_opt(&["--misdirected-write", "1", "--seed", "0"]);
+        for &(offset, moved) in &[(0, false), (1024, true)] {
+            let mut changes = vec![Change::Write {
+                offset,
+                data: vec![1; 512],
+            }];
+            inject_faults(&mut changes, &[0], &opt);
+            let new_offset = changes[0].as_write().unwrap().0;
+            assert_eq!(new_offset != offset, moved);
+            assert!(new_offset <= offset);
+        }
+
+        let mut journal = Journal::new(Vec::new());
+        let opt = mutate_opt(&["--misdirected-write", "1", "--sector-size", "0"]);
+        assert!(mutate_journal(&mut journal, &opt).is_err());
+    }
+
+    #[test]
+    fn test_pick_next_case() {
+        use Tested::*;
+        assert_eq!(pick_next_case(&[], 0), None);
+
+        // Endpoints first.
+        let mut tested = vec![Unknown; 5];
+        assert_eq!(pick_next_case(&tested, 0), Some(0));
+        tested[0] = Pass(1);
+        assert_eq!(pick_next_case(&tested, 0), Some(4));
+
+        // Bisect between different pass variants.
+        tested[4] = Pass(2);
+        assert_eq!(pick_next_case(&tested, 4), Some(2));
+        tested[2] = Pass(1);
+        assert_eq!(pick_next_case(&tested, 2), Some(3));
+
+        // Same variants: nothing to bisect. Pick the next untested case.
+        let tested = [Pass(1), Unknown, Unknown, Unknown, Pass(1)];
+        assert_eq!(pick_next_case(&tested, 2), Some(3));
+        assert_eq!(pick_next_case(&tested, 3), Some(1));
+
+        // Running and failed cases split bisect ranges.
+        let tested = [
+            Pass(1),
+            Unknown,
+            Running,
+            Unknown,
+            Unknown,
+            Unknown,
+            Pass(2),
+        ];
+        assert_eq!(pick_next_case(&tested, 2), Some(4));
+        let tested = [Pass(1), Unknown, Fail(1), Unknown, Pass(2)];
+        assert_eq!(pick_next_case(&tested, 0), Some(1));
+        let tested = [Pass(1), Running, Fail(1), Error, Pass(2)];
+        assert_eq!(pick_next_case(&tested, 0), None);
+    }
+
+    #[test]
+    fn test_binomial() {
+        assert_eq!(binomial(5, 0), 1);
+        assert_eq!(binomial(5, 2), 10);
+        assert_eq!(binomial(5, 5), 1);
+        assert_eq!(binomial(2, 5), 0);
+        assert_eq!(binomial(60, 30), 118264581564861424);
+        assert_eq!(binomial(200, 100), usize::MAX);
+    }
+
+    #[test]
+    fn test_sample_cases() {
+        let width = 12;
+        for &(strategy, count) in &[
+            ("random", 16),
+            ("prefix", width + 1),
+            ("prefix-plus-one", 16),
+            ("subset", 16),
+            ("coverage", 2),
+        ] {
+            let opt = gen_tests_opt(&["--max-cases-log2", "4", "--strategy", strategy]);
+            let mut rng = StdRng::seed_from_u64(0);
+            let cases = sample_cases(width, 16, &opt, &mut rng);
+            if strategy == "coverage" {
+                assert!(cases.len() >= count && cases.len() <= 16, "{}", strategy);
+                for i in 0..width {
+                    assert!(cases.iter().any(|bits| bits[i]), "{}", strategy);
+                    assert!(cases.iter().any(|bits| !bits[i]), "{}", strategy);
+                }
+            } else {
+                assert_eq!(cases.len(), count, "{}", strategy);
+            }
+            assert!(cases.iter().all(|bits| bits.len() == width), "{}", strategy);
+            let unique: HashSet<&Vec<bool>> = cases.iter().collect();
+            assert_eq!(unique.len(), cases.len(), "{}", strategy);
+        }
+
+        // Prefixes are evenly spaced if there are too many.
+        let opt = gen_tests_opt(&["--max-cases-log2", "2", "--strategy", "prefix"]);
+        let mut rng = StdRng::seed_from_u64(0);
+        let cases = sample_cases(9, 4, &opt, &mut rng);
+        let taken: Vec<usize> = cases
+            .iter()
+            .map(|bits| bits.iter().filter(|&&b| b).count())
+            .collect();
+        assert_eq!(taken, [0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn test_ddmin() {
+        let mut calls: HashMap<Vec<usize>, usize> = HashMap::new();
+        let fails = |subset: &[usize]| subset.contains(&3) && subset.contains(&7);
+        let minimal = ddmin((0..10).collect(), |subset| {
+            *calls.entry(subset.to_vec()).or_default() += 1;
+            fails(subset)
+        });
+        assert_eq!(minimal, vec![3, 7]);
+        // 1-minimal: removing any item no longer fails.
+        for i in 0..minimal.len() {
+            let mut subset = minimal.clone();
+            subset.remove(i);
+            assert!(!fails(&subset));
+        }
+        // Cached subsets are not tested again.
+        assert!(calls.values().all(|&n| n == 1));
+
+        assert_eq!(ddmin((0..10).collect(), |_| true), Vec::<usize>::new());
+        assert_eq!(ddmin(vec![4], |s| s == [4]), vec![4]);
+    }
+
+    #[test]
+    fn test_gen_tests_seed() {
+        let mut journal = Journal::new(vec![0; 64]);
+        for i in 0..12 {
+            journal.changes.push(Change::Write {
+                offset: i,
+                data: vec![1],
+            });
+        }
+        journal.changes.push(Change::Sync);
+
+        let filters = |strategy: &str, seed: u64| -> Vec<String> {
+            let opt = gen_tests_opt(&["--max-cases-log2", "3", "--strategy", strategy]);
+            gen_tests(&journal, &opt, seed)
+                .into_iter()
+                .map(|c| c.filter)
+                .collect()
+        };
+        for &strategy in &["random", "subset", "coverage"] {
+            assert_eq!(filters(strategy, 1), filters(strategy, 1), "{}", strategy);
+            assert_ne!(filters(strategy, 1), filters(strategy, 2), "{}", strategy);
+        }
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| Ok(3)).unwrap(), 3);
+        let error = catch_panic(|| panic!("job")).err().unwrap();
+        assert_eq!(error.to_string(), "verify job panicked");
+    }
+
+    #[test]
+    fn test_verify_command() {
+        let dir = tempdir().unwrap();
+        let script = dir.path().join("a script");
+        fs::write(&script, "#!/bin/sh\necho \"$1 $2\"; echo err >&2").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let result_dir = dir.path().join("results dir");
+        fs::create_dir(&result_dir).unwrap();
+
+        let exec = verify_command(&script.display().to_string(), Path::new("img"), &result_dir);
+        let status = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&exec)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(result_dir.join("stdout")).unwrap(),
+            b"verify img\n"
+        );
+        assert_eq!(fs::read(result_dir.join("stderr")).unwrap(), b"err\n");
+    }
+
+    #[test]
+    fn test_summary_groups() {
+        let tests: Vec<TestCase> = (0..7)
+            .map(|i| TestCase {
+                filter: format!("0:{}", i),
+                strategy: "exhaustive",
+                window: (0, 1),
+            })
+            .collect();
+        let tested = [
+            Tested::Fail(3),
+            Tested::Pass(0),
+            Tested::Error,
+            Tested::Pass(1),
+            Tested::Running,
+            Tested::Pass(0),
+            Tested::Unknown,
+        ];
+        let groups = summary_groups(&tests, &tested);
+        assert_eq!(
+            groups,
+            vec![
+                ("untested".to_string(), vec!["0:4", "0:6"]),
+                ("pass 0".to_string(), vec!["0:1", "0:5"]),
+                ("pass 1".to_string(), vec!["0:3"]),
+                ("fail 3".to_string(), vec!["0:0"]),
+                ("error".to_string(), vec!["0:2"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gen_tests_reorder() {
+        let write = |offset: usize, len: usize| Change::Write {
+            offset,
+            data: vec![offset as u8 + 1; len],
+        };
+        let reordered = |journal: &Journal| -> Vec<String> {
+            gen_tests(journal, &gen_tests_opt(&["--reorder", "--no-dedup"]), 0)
+                .into_iter()
+                .filter(|c| c.strategy == "reorder")
+                .map(|c| c.filter)
+                .collect()
+        };
+
+        // Two groups of overlapping writes are permuted separately.
+        let mut journal = Journal::new(vec![0; 16]);
+        journal.changes = vec![write(0, 4), write(2, 4), write(10, 2), write(11, 2)];
+        journal.changes.push(Change::Sync);
+        assert_eq!(reordered(&journal), ["0:1111/1,0", "0:1111/3,2"]);
+
+        // A chain of 3 writes has 5 orders besides the original one.
+        journal.changes = vec![write(0, 4), write(2, 4), write(5, 3), Change::Sync];
+        assert_eq!(
+            reordered(&journal),
+            [
+                "0:111/0,2,1",
+                "0:111/1,0,2",
+                "0:111/1,2,0",
+                "0:111/2,0,1",
+                "0:111/2,1,0"
+            ]
+        );
+
+        // FUA writes are not moved, but in-flight writes can land after them.
+        // Writes truncated by a later resize are not moved.
+        journal.changes = vec![
+            write(0, 4),
+            Change::FuaWrite {
+                offset: 2,
+                data: vec![9; 4],
+            },
+            write(8, 2),
+            write(8, 2),
+            Change::Resize { len: 9 },
+            Change::Sync,
+        ];
+        assert_eq!(reordered(&journal), ["0:11111/0"]);
+
+        // The number of cases is limited.
+        journal.changes = (0..8).map(|_| write(0, 4)).collect();
+        journal.changes.push(Change::Sync);
+        let opt = gen_tests_opt(&["--reorder", "--no-dedup", "-m", "3"]);
+        let cases = gen_tests(&journal, &opt, 0);
+        assert_eq!(cases.iter().filter(|c| c.strategy == "reorder").count(), 8);
+    }
+
+    #[test]
+    fn test_posix_unsupported_option() {
+        assert_eq!(posix_unsupported_option(&gen_tests_opt(&[])), None);
+        let args = ["--seed", "1", "--model", "anything-goes", "-m", "4"];
+        assert_eq!(posix_unsupported_option(&gen_tests_opt(&args)), None);
+        for &(args, name) in &[
+            (&["--no-datasync-barrier"][..], "--no-datasync-barrier"),
+            (&["--flush-barrier"], "--flush-barrier"),
+            (&["--reorder"], "--reorder"),
+            (&["--cache-model"], "--cache-model"),
+            (&["--no-dedup"], "--no-dedup"),
+            (&["--strategy", "prefix"], "--strategy"),
+            (&["--crash-points", "all"], "--crash-points"),
+        ] {
+            let opt = gen_tests_opt(args);
+            assert_eq!(posix_unsupported_option(&opt), Some(name));
+        }
+    }
+
+    #[test]
+    fn test_change_to_json() {
+        let journal = Journal::new(vec![0; 4]);
+        let hash = content_hash(&[0, 2]);
+        let data = vec![0, 2];
+        let cases = vec![
+            (
+                Change::Write {
+                    offset: 1,
+                    data: data.clone(),
+                },
+                json!({
+                    "index": 3,
+                    "kind": "Write",
+                    "offset": 1,
+                    "length": 2,
+                    "zero": false,
+                    "hash": hash,
+                }),
+            ),
+            (
+                Change::FuaWrite {
+                    offset: 0,
+                    data: vec![0; 3],
+                },
+                json!({
+                    "index": 3,
+                    "kind": "FuaWrite",
+                    "offset": 0,
+                    "length": 3,
+                    "zero": true,
+                    "hash": content_hash(&[0; 3]),
+                }),
+            ),
+            (Change::Sync, json!({"index": 3, "kind": "Sync"})),
+            (Change::DataSync, json!({"index": 3, "kind": "DataSync"})),
+            (Change::Flush, json!({"index": 3, "kind": "Flush"})),
+            (Change::Release, json!({"index": 3, "kind": "Release"})),
+            (
+                Change::Discard { offset: 2, len: 5 },
+                json!({"index": 3, "kind": "Discard", "offset": 2, "length": 5}),
+            ),
+            (
+                Change::Resize { len: 7 },
+                json!({"index": 3, "kind": "Resize", "length": 7}),
+            ),
+        ];
+        for (change, expected) in &cases {
+            assert_eq!(&change_to_json(&journal, 3, change, false), expected);
+        }
+
+        // Verbose output has the data.
+        let write = Change::Write { offset: 1, data };
+        let mut expected = cases[0].1.clone();
+        expected["data"] = json!([0, 2]);
+        assert_eq!(change_to_json(&journal, 3, &write, true), expected);
+        assert_eq!(change_to_json(&journal, 3, &Change::Sync, true), cases[2].1);
+
+        // In multi-file mode, the file name is included.
+        let journal = Journal::with_files(vec![
+            ("a".to_string(), Vec::new()),
+            ("b".to_string(), Vec::new()),
+        ]);
+        for (change, expected) in cases {
+            let mut expected = expected;
+            expected["file"] = json!("b");
+            let change = change.with_file(Some(1));
+            assert_eq!(change_to_json(&journal, 3, &change, false), expected);
+        }
+    }
+
+    #[test]
+    fn test_tree_change_to_json() {
+        let write = TreeChange::Write {
+            ino: 5,
+            offset: 2,
+            data: vec![1, 2],
+        };
+        let hash = content_hash(&[1, 2]);
+        assert_eq!(
+            tree_change_to_json(0, &write, false),
+            json!({
+                "index": 0,
+                "kind": "Write",
+                "ino": 5,
+                "offset": 2,
+                "length": 2,
+                "zero": false,
+                "hash": hash,
+            })
+        );
+        assert_eq!(
+            tree_change_to_json(0, &write, true),
+            json!({
+                "index": 0,
+                "kind": "Write",
+                "ino": 5,
+                "offset": 2,
+                "data": [1, 2],
+                "length": 2,
+                "zero": false,
+                "hash": hash,
+            })
+        );
+        let create = TreeChange::Create {
+            parent: 1,
+            name: "a".to_string(),
+            mode: 0o644,
+        };
+        assert_eq!(
+            tree_change_to_json(1, &create, true),
+            json!({"index": 1, "kind": "Create", "parent": 1, "name": "a", "mode": 0o644})
+        );
+        let rename = TreeChange::Rename {
+            parent: 1,
+            name: "a".to_string(),
+            new_parent: 2,
+            new_name: "b".to_string(),
+        };
+        assert_eq!(
+            tree_change_to_json(2, &rename, false),
+            json!({
+                "index": 2,
+                "kind": "Rename",
+                "parent": 1,
+                "name": "a",
+                "new_parent": 2,
+                "new_name": "b",
+            })
+        );
+        assert_eq!(
+            tree_change_to_json(3, &TreeChange::Fsync { ino: 5 }, false),
+            json!({"index": 3, "kind": "Fsync", "ino": 5})
+        );
+        assert_eq!(
+            tree_change_to_json(
+                4,
+                &TreeChange::Chmod {
+                    ino: 5,
+                    mode: 0o600
+                },
+                false
+            ),
+            json!({"index": 4, "kind": "Chmod", "ino": 5, "mode": 0o600})
+        );
+    }
+
+    #[test]
+    fn test_gen_tests_crash_points() {
+        let mut journal = Journal::new(vec![0; 4]);
+        let write = |offset| Change::Write {
+            offset,
+            data: vec![1],
+        };
+        journal.changes = vec![write(0), write(1), Change::Sync, write(2)];
+        let opt = gen_tests_opt(&["--crash-points", "all", "--no-dedup"]);
+        let cases: Vec<((usize, usize), String)> = gen_tests(&journal, &opt, 0)
+            .into_iter()
+            .map(|c| (c.window, c.filter))
+            .collect();
+        let case = |start, end, filter: &str| ((start, end), filter.to_string());
+        assert_eq!(
+            cases,
+            [
+                case(0, 0, "0:@0"),
+                case(0, 1, "0:0@1"),
+                case(0, 1, "0:1@1"),
+                case(0, 2, "0:00@2"),
+                case(0, 2, "0:01@2"),
+                case(0, 2, "0:10@2"),
+                case(0, 2, "0:11@2"),
+                case(3, 3, "3:@3"),
+                case(3, 4, "3:0@4"),
+                case(3, 4, "3:1@4"),
+            ]
+        );
+        // Changes from the crash point are not taken.
+        for ((_, end), filter) in &cases {
+            let filter: ChangeFilter = filter.parse().unwrap();
+            assert!((*end..journal.changes.len()).all(|i| !filter.should_take(i)));
+        }
+    }
+
+    #[test]
+    fn test_materialize_twice() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let paths = |base: &str, posix: bool| PathOpt {
+            base: path(base),
+            changes: path(&format!("{}.changes", base)),
+            posix,
+        };
+        let materialize_to = |paths: &PathOpt, dest: &Path| -> io::Result<()> {
+            remove_dest(dest)?;
+            materialize(paths, &FilterOpt::default(), dest)
+        };
+
+        // Single file.
+        let single = paths("single", false);
+        Journal::new(vec![1, 2])
+            .dump(&single.base, &single.changes)
+            .unwrap();
+        for _ in 0..2 {
+            materialize_to(&single, &path("single.out")).unwrap();
+            assert_eq!(fs::read(path("single.out")).unwrap(), [1, 2]);
+        }
+
+        // Multiple files. Files left by the last run are removed.
+        let multi = paths("multi", false);
+        Journal::with_files(vec![("a".to_string(), vec![3])])
+            .dump(&multi.base, &multi.changes)
+            .unwrap();
+        for _ in 0..2 {
+            materialize_to(&multi, &path("multi.out")).unwrap();
+            assert_eq!(fs::read(path("multi.out/a")).unwrap(), [3]);
+            fs::write(path("multi.out/stale"), "").unwrap();
+        }
+
+        // POSIX mode, with a read-only directory.
+        let posix = paths("posix", true);
+        fs::create_dir_all(path("posix/ro")).unwrap();
+        fs::write(path("posix/ro/f"), "x").unwrap();
+        fs::set_permissions(path("posix/ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        for _ in 0..2 {
+            materialize_to(&posix, &path("posix.out")).unwrap();
+            assert_eq!(fs::read(path("posix.out/ro/f")).unwrap(), b"x");
+            let mode = fs::metadata(path("posix.out/ro"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o555);
+        }
+        remove_dest(&path("posix.out")).unwrap();
+        fs::set_permissions(path("posix/ro"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_minimize_keeps_crash_point() {
+        let dir = tempdir().unwrap();
+        let paths = PathOpt {
+            base: dir.path().join("base"),
+            changes: dir.path().join("changes"),
+            posix: false,
+        };
+        let mut journal = Journal::new(vec![0; 4]);
+        for offset in 0..4 {
+            journal.changes.push(Change::Write {
+                offset,
+                data: vec![1],
+            });
+        }
+        journal.dump(&paths.base, &paths.changes).unwrap();
+        // Fail if the 2nd change is taken but the 4th is not.
+        let script = dir.path().join("suite");
+        fs::write(
+            &script,
+            "#!/bin/sh\n[ \"$1\" = verify ] || exit 0\nset -- $(od -An -tu1 -v \"$2\")\n\
+             [ \"$2\" = 1 ] && [ \"$4\" = 0 ] && exit 1\nexit 0\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let minimize = |filter: &str| {
+            let filter = FilterOpt {
+                filter: filter.to_string(),
+                replay: Default::default(),
+            };
+            let run = RunOpt { sudo: false };
+            let dest = dir.path().join("dest");
+            let script = script.display().to_string();
+            minimize(&script, &paths, &filter, &run, true, &dest).unwrap()
+        };
+        // The 4th change is after the crash point, so it is never taken.
+        assert_eq!(minimize("^0,2@3"), ("^@3".to_string(), vec![]));
+        assert_eq!(minimize("0:010"), ("^@3".to_string(), vec![]));
+        // Without a crash point, the 4th change must stay dropped.
+        assert_eq!(minimize("^0,2,3"), ("^3".to_string(), vec![3]));
+    }
+
+    #[test]
+    fn test_dedup_cases() {
+        let mut journal = Journal::new(vec![0; 5000]);
+        let write = |offset, data: &[u8]| Change::Write {
+            offset,
+            data: data.to_vec(),
+        };
+        journal.changes.push(write(4096, &[1]));
+        journal.changes.push(Change::Sync);
+        // Writes rewriting identical data produce identical images.
+        journal.changes.push(write(0, &[2]));
+        journal.changes.push(write(0, &[2]));
+        journal.changes.push(write(4097, &[0]));
+        journal.changes.push(Change::Sync);
+
+        let filters =
+            |cases: Vec<TestCase>| -> Vec<String> { cases.into_iter().map(|c| c.filter).collect() };
+        let cases = gen_tests(&journal, &gen_tests_opt(&["--no-dedup"]), 0);
+        assert_eq!(cases.len(), 10);
+        let cases = gen_tests(&journal, &gen_tests_opt(&[]), 0);
+        // "2:000" is the same as "0:1".
+        assert_eq!(filters(cases), ["0:0", "0:1", "2:010"]);
+    }
+}
@@ -1,40 +1,77 @@
+use crate::image::Image;
 use crate::journal::Change;
 use crate::vendor::fuse::FileAttr;
 use crate::vendor::fuse::FileType;
 use crate::vendor::fuse::Filesystem;
 use crate::vendor::fuse::ReplyAttr;
 use crate::vendor::fuse::ReplyData;
+use crate::vendor::fuse::ReplyDirectory;
 use crate::vendor::fuse::ReplyEmpty;
 use crate::vendor::fuse::ReplyEntry;
+use crate::vendor::fuse::ReplyOpen;
 use crate::vendor::fuse::ReplyStatfs;
 use crate::vendor::fuse::ReplyWrite;
 use crate::vendor::fuse::Request;
 use std::ffi::OsStr;
 use std::time::Duration;
+use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 const BLOCK_SIZE: usize = 512;
 
+// See fallocate(2).
+const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
 /// Fuse state for "outagefs" - a single file filesystem recording write and
 /// flush operations.
+///
+/// In multi-file mode, the filesystem is a directory of files instead. Changes
+/// to all files are recorded in a single list, tagged with file indexes.
 pub struct FuseOutageFilesystem<'a> {
-    /// The filesystem is exposed as a single file. This is its content.
-    data: Vec<u8>,
+    /// Content of the exposed files. In single-file mode, the filesystem is
+    /// exposed as a single file and this has only one item.
+    files: Vec<Image>,
+
+    /// File names in multi-file mode. Empty in single-file mode.
+    names: Vec<String>,
 
     /// Modifications to the filesystem.
     changes: &'a mut Vec<Change>,
 }
 
+/// Inode of the root directory in multi-file mode. File `i` uses inode `i + 2`.
+const ROOT_INO: u64 = 1;
+
+const TTL: Duration = Duration::from_secs(60);
+
 impl<'a> FuseOutageFilesystem<'a> {
     fn block_count(&self) -> usize {
-        (self.data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE
+        let len: usize = self.files.iter().map(|d| d.len()).sum();
+        (len + BLOCK_SIZE - 1) / BLOCK_SIZE
     }
 
-    fn attr(&self) -> FileAttr {
-        FileAttr {
+    fn is_multi_file(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    /// Index of the file for the given inode.
+    fn file_index(&self, ino: u64) -> Option<usize> {
+        if !self.is_multi_file() {
+            Some(0)
+        } else if ino > ROOT_INO && ((ino - ROOT_INO - 1) as usize) < self.files.len() {
+            Some((ino - ROOT_INO - 1) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn attr(&self, ino: u64) -> FileAttr {
+        let mut attr = FileAttr {
             ino: 1,
-            size: self.data.len() as u64,
-            blocks: self.block_count() as _,
+            size: 0,
+            blocks: 0,
             atime: UNIX_EPOCH,
             mtime: UNIX_EPOCH,
             ctime: UNIX_EPOCH,
@@ -46,58 +83,316 @@ impl<'a> FuseOutageFilesystem<'a> {
             gid: 0,
             rdev: 1,
             flags: 0,
+        };
+        match self.file_index(ino) {
+            Some(i) => {
+                let len = self.files[i].len();
+                if self.is_multi_file() {
+                    attr.ino = ino;
+                }
+                attr.size = len as u64;
+                attr.blocks = ((len + BLOCK_SIZE - 1) / BLOCK_SIZE) as _;
+            }
+            None => {
+                attr.kind = FileType::Directory;
+                attr.perm = 0o755;
+                attr.nlink = 2;
+            }
         }
+        attr
     }
 
-    pub fn new(data: Vec<u8>, changes: &'a mut Vec<Change>) -> Self {
-        Self { data, changes }
+    pub fn new(data: Image, changes: &'a mut Vec<Change>) -> Self {
+        Self {
+            files: vec![data],
+            names: Vec::new(),
+            changes,
+        }
     }
+
+    /// Create a multi-file filesystem from file names and their content.
+    pub fn with_files(files: Vec<(String, Image)>, changes: &'a mut Vec<Change>) -> Self {
+        let (names, files) = files.into_iter().unzip();
+        Self {
+            files,
+            names,
+            changes,
+        }
+    }
+
+    /// Record a change to the given file.
+    fn push(&mut self, file: usize, change: Change) {
+        let file = if self.is_multi_file() {
+            Some(file)
+        } else {
+            None
+        };
+        self.changes.push(change.with_file(file));
+    }
+
+    fn set_len(&mut self, file: usize, len: usize) {
+        self.files[file].set_len(len);
+        self.push(file, Change::Resize { len });
+    }
+
+    /// Write to the given file with the open flags, and record the write.
+    fn record_write(&mut self, file: usize, open_flags: i32, offset: usize, data: &[u8]) {
+        // Writes past the end grow the file.
+        self.files[file].write(offset, data);
+        // O_SYNC writes are treated as "preflush" + "forced unit access".
+        // O_DSYNC writes are treated as "forced unit access".
+        if open_flags & libc::O_SYNC == libc::O_SYNC {
+            self.push_sync(file, Change::Sync);
+        }
+        let change = if open_flags & libc::O_DSYNC != 0 {
+            Change::FuaWrite {
+                offset,
+                data: data.to_vec(),
+            }
+        } else {
+            Change::Write {
+                offset,
+                data: data.to_vec(),
+            }
+        };
+        self.push(file, change);
+    }
+
+    fn push_sync(&mut self, file: usize, change: Change) {
+        let file = if self.is_multi_file() {
+            Some(file)
+        } else {
+            None
+        };
+        let change = change.with_file(file);
+        if self.changes.last() == Some(&change) {
+            // No need to record the same Sync if it was just recorded.
+        } else {
+            self.changes.push(change);
+        }
+    }
+}
+
+/// Translate a fallocate request on a file of `file_len` bytes to changes.
+///
+/// Return an errno for invalid or unsupported modes.
+fn fallocate_changes(
+    file_len: usize,
+    offset: i64,
+    length: i64,
+    mode: u32,
+) -> Result<Vec<Change>, i32> {
+    if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+        // Collapsing, inserting or unsharing ranges is not supported.
+        return Err(libc::EOPNOTSUPP);
+    }
+    if offset < 0 || length <= 0 {
+        return Err(libc::EINVAL);
+    }
+    let end_requested = (offset as usize)
+        .checked_add(length as usize)
+        .ok_or(libc::EFBIG)?;
+    let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+    // Part of the range within the file.
+    let end = end_requested.min(file_len);
+    let offset = (offset as usize).min(end);
+    let len = end - offset;
+    let mut changes = Vec::new();
+    if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+        // Loop devices translate discard (TRIM) to punch hole, which
+        // requires KEEP_SIZE.
+        if !keep_size || mode & FALLOC_FL_ZERO_RANGE != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+        if len > 0 {
+            changes.push(Change::Discard { offset, len });
+        }
+        return Ok(changes);
+    }
+    if mode & FALLOC_FL_ZERO_RANGE != 0 && len > 0 {
+        changes.push(Change::Write {
+            offset,
+            data: vec![0; len],
+        });
+    }
+    if !keep_size && end_requested > file_len {
+        // Growing the file fills zeros.
+        changes.push(Change::Resize { len: end_requested });
+    }
+    Ok(changes)
 }
 
 impl<'a> Filesystem for FuseOutageFilesystem<'a> {
-    fn getattr(&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
-        reply.attr(&Duration::from_secs(60), &self.attr());
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        reply.attr(&TTL, &self.attr(ino));
     }
 
-    fn lookup(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
-        reply.entry(&Duration::from_secs(60), &self.attr(), 0);
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        if let (Some(size), Some(i)) = (size, self.file_index(ino)) {
+            if size as usize != self.files[i].len() {
+                self.set_len(i, size as usize);
+            }
+        }
+        reply.attr(&TTL, &self.attr(ino));
     }
 
-    fn read(&mut self, _: &Request, _ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        let offset = offset as usize;
-        let size = size as usize;
-        let end = (offset + size).min(self.data.len());
-        reply.data(&self.data[offset..end]);
+    fn lookup(&mut self, _req: &Request, _parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if !self.is_multi_file() {
+            return reply.entry(&TTL, &self.attr(ROOT_INO), 0);
+        }
+        match self.names.iter().position(|n| OsStr::new(n) == name) {
+            Some(i) => reply.entry(&TTL, &self.attr(i as u64 + ROOT_INO + 1), 0),
+            None => reply.error(libc::ENOENT),
+        }
     }
 
-    fn write(
+    fn readdir(
         &mut self,
         _req: &Request,
         _ino: u64,
         _fh: u64,
         offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let mut entries = vec![
+            (ROOT_INO, FileType::Directory, "."),
+            (ROOT_INO, FileType::Directory, ".."),
+        ];
+        for (i, name) in self.names.iter().enumerate() {
+            entries.push((
+                i as u64 + ROOT_INO + 1,
+                FileType::RegularFile,
+                name.as_str(),
+            ));
+        }
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
+        // Use the file handle to remember the open flags. The "write" callback
+        // does not get them otherwise.
+        reply.opened(flags as u64, 0);
+    }
+
+    fn read(&mut self, _: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let data = match self.file_index(ino) {
+            Some(i) => &self.files[i],
+            None => return reply.error(libc::EISDIR),
+        };
+        reply.data(&data.read(offset as usize, size as usize));
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let offset = offset as usize;
-        self.data[offset..offset + data.len()].copy_from_slice(data);
-        self.changes.push(Change::Write {
-            offset,
-            data: data.to_vec(),
-        });
+        let i = match self.file_index(ino) {
+            Some(i) => i,
+            None => return reply.error(libc::EISDIR),
+        };
+        self.record_write(i, fh as i32, offset as usize, data);
         reply.written(data.len() as u32);
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        if let Some(Change::Sync) = self.changes.last() {
-            // No need to record Sync if the last change was Sync.
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        if let Some(i) = self.file_index(ino) {
+            self.push_sync(i, Change::Flush);
+        }
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if let Some(i) = self.file_index(ino) {
+            self.push_sync(i, Change::Release);
+        }
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let change = if datasync {
+            Change::DataSync
         } else {
-            self.changes.push(Change::Sync);
+            Change::Sync
+        };
+        if let Some(i) = self.file_index(ino) {
+            self.push_sync(i, change);
         }
         reply.ok();
     }
 
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: u32,
+        reply: ReplyEmpty,
+    ) {
+        let i = match self.file_index(ino) {
+            Some(i) => i,
+            None => return reply.error(libc::EISDIR),
+        };
+        match fallocate_changes(self.files[i].len(), offset, length, mode) {
+            Ok(changes) => {
+                for change in changes {
+                    match &change {
+                        Change::Write { offset, data } => self.files[i].write(*offset, data),
+                        // A punched hole reads back as zeros until the crash,
+                        // whatever `--discard` is. `DiscardMode` only applies
+                        // when replaying.
+                        Change::Discard { offset, len } => self.files[i]
+                            .modify(*offset..offset + len, |buf, _| {
+                                buf.iter_mut().for_each(|b| *b = 0)
+                            }),
+                        Change::Resize { len } => self.files[i].set_len(*len),
+                        _ => unreachable!("fallocate only writes, discards or resizes"),
+                    }
+                    self.push(i, change);
+                }
+                reply.ok();
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
     /// Get file system statistics.
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         let blocks = self.block_count();
@@ -105,3 +400,97 @@ impl<'a> Filesystem for FuseOutageFilesystem<'a> {
         reply.statfs(blocks as _, 0, 0, 0, 0, BLOCK_SIZE as _, namelen, 0);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallocate_changes() {
+        let keep = FALLOC_FL_KEEP_SIZE;
+        let punch = FALLOC_FL_PUNCH_HOLE | keep;
+        let zero = FALLOC_FL_ZERO_RANGE;
+        let f = |offset, length, mode| fallocate_changes(10, offset, length, mode);
+        let discard = |offset, len| Change::Discard { offset, len };
+        let zeros = |offset, len| Change::Write {
+            offset,
+            data: vec![0; len],
+        };
+        let resize = |len| Change::Resize { len };
+
+        assert_eq!(f(2, 4, punch), Ok(vec![discard(2, 4)]));
+        assert_eq!(f(8, 4, punch), Ok(vec![discard(8, 2)]));
+        assert_eq!(f(12, 4, punch), Ok(vec![]));
+        assert_eq!(f(2, 4, FALLOC_FL_PUNCH_HOLE), Err(libc::EOPNOTSUPP));
+
+        // Zeroing past the end grows the file, unless KEEP_SIZE is set.
+        assert_eq!(f(2, 4, zero), Ok(vec![zeros(2, 4)]));
+        assert_eq!(f(8, 4, zero), Ok(vec![zeros(8, 2), resize(12)]));
+        assert_eq!(f(12, 4, zero), Ok(vec![resize(16)]));
+        assert_eq!(f(8, 4, zero | keep), Ok(vec![zeros(8, 2)]));
+        assert_eq!(f(12, 4, zero | keep), Ok(vec![]));
+
+        assert_eq!(f(0, 20, 0), Ok(vec![resize(20)]));
+        assert_eq!(f(0, 5, 0), Ok(vec![]));
+        assert_eq!(f(0, 20, keep), Ok(vec![]));
+        assert_eq!(f(0, i64::MAX, keep), Ok(vec![]));
+
+        // Collapse, insert and unshare ranges.
+        for &mode in &[0x08, 0x20, 0x40, 0x08 | keep] {
+            assert_eq!(f(0, 4, mode), Err(libc::EOPNOTSUPP));
+        }
+        assert_eq!(f(-1, 4, 0), Err(libc::EINVAL));
+        assert_eq!(f(0, 0, 0), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_record_write_and_sync() {
+        let mut changes = Vec::new();
+        let mut fs = FuseOutageFilesystem::new(Image::from(vec![0; 4]), &mut changes);
+        fs.record_write(0, libc::O_SYNC, 0, &[1]);
+        fs.record_write(0, libc::O_DSYNC, 1, &[2]);
+        fs.record_write(0, 0, 2, &[3]);
+        fs.push_sync(0, Change::Flush);
+        fs.push_sync(0, Change::Flush);
+        fs.push_sync(0, Change::Release);
+        assert_eq!(fs.files[0].to_vec(), vec![1, 2, 3, 0]);
+        let write = |offset, data: u8| Change::Write {
+            offset,
+            data: vec![data],
+        };
+        assert_eq!(
+            changes,
+            vec![
+                Change::Sync,
+                Change::FuaWrite {
+                    offset: 0,
+                    data: vec![1],
+                },
+                Change::FuaWrite {
+                    offset: 1,
+                    data: vec![2],
+                },
+                write(2, 3),
+                Change::Flush,
+                Change::Release,
+            ]
+        );
+
+        // In multi-file mode, changes are tagged with file indexes.
+        let mut changes = Vec::new();
+        let files = vec![
+            ("a".to_string(), Image::from(vec![])),
+            ("b".to_string(), Image::from(vec![])),
+        ];
+        let mut fs = FuseOutageFilesystem::with_files(files, &mut changes);
+        fs.record_write(1, 0, 0, &[5]);
+        fs.push_sync(1, Change::Flush);
+        assert_eq!(
+            changes,
+            vec![
+                write(0, 5).with_file(Some(1)),
+                Change::Flush.with_file(Some(1)),
+            ]
+        );
+    }
+}
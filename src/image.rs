@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Size of pages tracked by `Image`.
+const PAGE_SIZE: usize = 4096;
+
+/// Read-only initial data of an image.
+///
+/// Data loaded from a file is mmapped, so large images are not read into
+/// memory. The file must not be truncated or modified while it is mapped.
+/// Otherwise, the data changes unexpectedly, or reading it crashes the
+/// process with `SIGBUS`.
+pub enum Base {
+    /// A mapped file, with its device and inode numbers.
+    Mapped {
+        ptr: *const u8,
+        len: usize,
+        id: (u64, u64),
+    },
+    Owned(Vec<u8>),
+}
+
+// The mapping is read-only.
+unsafe impl Send for Base {}
+unsafe impl Sync for Base {}
+
+impl Base {
+    /// Map a file read-only.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let meta = file.metadata()?;
+        let len = meta.len() as usize;
+        if len == 0 {
+            // mmap does not support empty mappings.
+            return Ok(Base::Owned(Vec::new()));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Base::Mapped {
+            ptr: ptr as *const u8,
+            len,
+            id: (meta.dev(), meta.ino()),
+        })
+    }
+
+    /// Test if this is a mapping of the file at `path`.
+    pub fn is_mapping_of(&self, path: &Path) -> bool {
+        match self {
+            Base::Mapped { len, id, .. } => match fs::metadata(path) {
+                Ok(meta) => (meta.dev(), meta.ino()) == *id && meta.len() as usize == *len,
+                Err(_) => false,
+            },
+            Base::Owned(_) => false,
+        }
+    }
+}
+
+impl Deref for Base {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Base::Mapped { ptr, len, .. } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+            Base::Owned(data) => data,
+        }
+    }
+}
+
+impl Drop for Base {
+    fn drop(&mut self) {
+        if let Base::Mapped { ptr, len, .. } = *self {
+            unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+        }
+    }
+}
+
+impl From<Vec<u8>> for Base {
+    fn from(data: Vec<u8>) -> Self {
+        Base::Owned(data)
+    }
+}
+
+impl PartialEq for Base {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl fmt::Debug for Base {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Base({} bytes)", self.len())
+    }
+}
+
+/// Copy-on-write image: modified pages on top of a shared read-only base.
+///
+/// Cloning an image is cheap. Modified pages are shared until they are
+/// modified again.
+#[derive(Clone, Debug)]
+pub struct Image {
+    base: Arc<Base>,
+
+    /// Length of `base` that is still visible. Shrinking the image hides
+    /// the rest, so growing it again reads zeros.
+    base_len: usize,
+
+    len: usize,
+
+    /// Modified pages by page index. Bytes past `len` are zeros.
+    pages: BTreeMap<usize, Arc<Vec<u8>>>,
+}
+
+impl Image {
+    pub fn new(base: Arc<Base>) -> Self {
+        let len = base.len();
+        Self {
+            base,
+            base_len: len,
+            len,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read up to `size` bytes at `offset`.
+    pub fn read(&self, offset: usize, size: usize) -> Vec<u8> {
+        let end = (offset + size).min(self.len);
+        let offset = offset.min(end);
+        let mut result = Vec::with_capacity(end - offset);
+        for chunk in self.chunks(offset..end) {
+            result.extend_from_slice(chunk);
+        }
+        result
+    }
+
+    /// Write `data` at `offset`. Writes past the end grow the image.
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        if end > self.len {
+            self.set_len(end);
+        }
+        self.modify(offset..end, |buf, buf_offset| {
+            let start = buf_offset - offset;
+            buf.copy_from_slice(&data[start..start + buf.len()]);
+        });
+    }
+
+    /// Change the length. Growing the image fills zeros.
+    pub fn set_len(&mut self, len: usize) {
+        if len < self.len {
+            self.base_len = self.base_len.min(len);
+            self.pages.split_off(&(len / PAGE_SIZE + 1));
+            if let Some(page) = self.pages.get_mut(&(len / PAGE_SIZE)) {
+                Arc::make_mut(page)[len % PAGE_SIZE..]
+                    .iter_mut()
+                    .for_each(|b| *b = 0);
+            }
+        }
+        self.len = len;
+    }
+
+    /// Modify bytes in `range`, which must be within the image, page by page.
+    /// `f` receives a slice and its offset in the image.
+    pub fn modify(&mut self, range: Range<usize>, mut f: impl FnMut(&mut [u8], usize)) {
+        assert!(range.end <= self.len, "modify past the end");
+        let mut offset = range.start;
+        while offset < range.end {
+            let index = offset / PAGE_SIZE;
+            let page_start = index * PAGE_SIZE;
+            let page_end = (page_start + PAGE_SIZE).min(range.end);
+            let page = self.page_mut(index);
+            f(
+                &mut page[offset - page_start..page_end - page_start],
+                offset,
+            );
+            offset = page_end;
+        }
+    }
+
+    /// Return the full content.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.read(0, self.len)
+    }
+
+    /// Write the full content to `out`.
+    pub fn write_to(&self, out: &mut impl io::Write) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < self.len {
+            let size = (PAGE_SIZE * 256).min(self.len - offset);
+            out.write_all(&self.read(offset, size))?;
+            offset += size;
+        }
+        Ok(())
+    }
+
+    /// Return the content of a page within the length.
+    pub fn page(&self, index: usize) -> Vec<u8> {
+        self.read(index * PAGE_SIZE, PAGE_SIZE)
+    }
+
+    /// Return indexes of pages that might have content different from
+    /// `other`.
+    ///
+    /// Pages shared with `other` are skipped, so this is cheap if one image is
+    /// cloned from the other with a few changes.
+    pub fn changed_pages(&self, other: &Image) -> Vec<usize> {
+        let mut indexes = BTreeSet::new();
+        let mut add_range = |a: usize, b: usize| {
+            if a != b {
+                indexes.extend(a.min(b) / PAGE_SIZE..=(a.max(b) - 1) / PAGE_SIZE);
+            }
+        };
+        if Arc::ptr_eq(&self.base, &other.base) {
+            add_range(self.base_len, other.base_len);
+            add_range(self.len, other.len);
+        } else {
+            add_range(0, self.len.max(other.len));
+        }
+        for (index, page) in &self.pages {
+            let shared = other.pages.get(index).map(|p| Arc::ptr_eq(p, page));
+            if shared != Some(true) {
+                indexes.insert(*index);
+            }
+        }
+        for index in other.pages.keys() {
+            if !self.pages.contains_key(index) {
+                indexes.insert(*index);
+            }
+        }
+        indexes.into_iter().collect()
+    }
+
+    /// Get a modified page, copying it from `base` if it is not modified, or
+    /// from other images if it is shared.
+    fn page_mut(&mut self, index: usize) -> &mut [u8] {
+        let base = &self.base;
+        let base_len = self.base_len;
+        let page = self.pages.entry(index).or_insert_with(|| {
+            let mut page = vec![0; PAGE_SIZE];
+            let start = (index * PAGE_SIZE).min(base_len);
+            let end = (start + PAGE_SIZE).min(base_len);
+            page[..end - start].copy_from_slice(&base[start..end]);
+            Arc::new(page)
+        });
+        &mut Arc::make_mut(page)[..]
+    }
+
+    /// Iterate through content in `range` as slices of modified pages,
+    /// `base`, or zeros.
+    fn chunks(&self, range: Range<usize>) -> impl Iterator<Item = &[u8]> {
+        let mut offset = range.start;
+        std::iter::from_fn(move || {
+            if offset >= range.end {
+                return None;
+            }
+            let index = offset / PAGE_SIZE;
+            let page_start = index * PAGE_SIZE;
+            let page_end = (page_start + PAGE_SIZE).min(range.end);
+            let chunk: &[u8] = match self.pages.get(&index) {
+                Some(page) => &page[offset - page_start..page_end - page_start],
+                None if offset < self.base_len => &self.base[offset..page_end.min(self.base_len)],
+                None => &ZEROS[..page_end - offset],
+            };
+            offset += chunk.len();
+            Some(chunk)
+        })
+    }
+}
+
+static ZEROS: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+impl PartialEq for Image {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && (self.changed_pages(other).into_iter()).all(|i| self.page(i) == other.page(i))
+    }
+}
+
+impl From<Vec<u8>> for Image {
+    fn from(data: Vec<u8>) -> Self {
+        Self::new(Arc::new(Base::from(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Run `op` on both an image and a plain Vec, and compare them.
+    fn check(image: &mut Image, data: &mut Vec<u8>, op: &dyn Fn(&mut Image, &mut Vec<u8>)) {
+        op(image, data);
+        assert_eq!(image.len(), data.len());
+        assert_eq!(&image.to_vec(), data);
+    }
+
+    #[test]
+    fn test_image_matches_vec() {
+        let base: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let mut image = Image::from(base.clone());
+        let mut data = base;
+        check(&mut image, &mut data, &|i, d| {
+            i.write(4000, &[1; 200]);
+            d[4000..4200].copy_from_slice(&[1; 200]);
+        });
+        // Shrink, then grow. Grown bytes are zeros.
+        check(&mut image, &mut data, &|i, d| {
+            i.set_len(4100);
+            d.truncate(4100);
+        });
+        check(&mut image, &mut data, &|i, d| {
+            i.set_len(9000);
+            d.resize(9000, 0);
+        });
+        check(&mut image, &mut data, &|i, d| {
+            i.write(12000, &[2; 3]);
+            d.resize(12000, 0);
+            d.extend_from_slice(&[2; 3]);
+        });
+        check(&mut image, &mut data, &|i, d| {
+            i.modify(100..5000, |buf, offset| {
+                buf.iter_mut()
+                    .enumerate()
+                    .for_each(|(j, b)| *b = (offset + j) as u8 ^ 0xff)
+            });
+            (100..5000).for_each(|j| d[j] = j as u8 ^ 0xff);
+        });
+        assert_eq!(image.read(4095, 3), data[4095..4098].to_vec());
+        assert_eq!(image.read(11999, 100), data[11999..].to_vec());
+        assert_eq!(image.read(20000, 1), Vec::<u8>::new());
+
+        // Clones share pages until modified.
+        let snapshot = image.clone();
+        let snapshot_data = data.clone();
+        check(&mut image, &mut data, &|i, d| {
+            i.write(4090, &[3; 10]);
+            d[4090..4100].copy_from_slice(&[3; 10]);
+        });
+        assert_eq!(snapshot.to_vec(), snapshot_data);
+
+        let mut out = Vec::new();
+        image.write_to(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_image_changed_pages() {
+        let base = Image::from(vec![1; PAGE_SIZE * 3]);
+        let mut image = base.clone();
+        assert!(image.changed_pages(&base).is_empty());
+
+        // Rewriting identical data changes a page, but not the content.
+        image.write(10, &[1; 10]);
+        assert_eq!(image.changed_pages(&base), vec![0]);
+        assert_eq!(image, base);
+
+        image.write(PAGE_SIZE + 1, &[2]);
+        assert_eq!(image.changed_pages(&base), vec![0, 1]);
+        assert_eq!(image.page(1)[1], 2);
+        assert_ne!(image, base);
+
+        // Shrinking changes the last page. Growing again reads zeros.
+        let mut image = base.clone();
+        image.set_len(PAGE_SIZE + 1);
+        image.set_len(PAGE_SIZE * 3);
+        assert_eq!(image.changed_pages(&base), vec![1, 2]);
+        assert_eq!(image.page(2), vec![0; PAGE_SIZE]);
+
+        // Images with different bases are compared page by page.
+        let other = Image::from(vec![1; PAGE_SIZE * 3]);
+        assert_eq!(other.changed_pages(&base), vec![0, 1, 2]);
+        assert_eq!(other, base);
+        assert_ne!(Image::from(vec![1; PAGE_SIZE * 2]), base);
+    }
+
+    #[test]
+    fn test_image_mmap() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("base");
+        fs::write(&path, b"abcdef").unwrap();
+        let base = Arc::new(Base::open(&path).unwrap());
+        let mut image = Image::new(base.clone());
+        image.write(2, b"XY");
+        assert_eq!(image.to_vec(), b"abXYef");
+        assert_eq!(&base[..], b"abcdef");
+        assert!(base.is_mapping_of(&path));
+        assert!(!base.is_mapping_of(dir.path()));
+        assert!(!Base::from(b"abcdef".to_vec()).is_mapping_of(&path));
+
+        fs::write(&path, b"").unwrap();
+        assert_eq!(Image::new(Arc::new(Base::open(&path).unwrap())).len(), 0);
+    }
+}
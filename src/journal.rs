@@ -1,23 +1,38 @@
 use crate::errors::Context;
+use crate::image::Base;
+use crate::image::Image;
 use crate::vendor::fuse;
 use log::debug;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
+use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
-use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
 
 /// Represent data and a list of changes to it.
 #[derive(Debug, Clone)]
 pub struct Journal {
     /// Initial data.
-    pub initial_data: Rc<Vec<u8>>,
+    pub initial_data: Arc<Base>,
 
     /// Changes applied to the initial data.
     pub changes: Vec<Change>,
+
+    /// How to replay `Discard` changes.
+    pub discard_mode: DiscardMode,
+
+    /// Names and initial data of files in multi-file mode. Empty in
+    /// single-file mode.
+    ///
+    /// In multi-file mode, `initial_data` is unused, and changes to file `i`
+    /// are wrapped in `Change::File { file: i, .. }`.
+    pub files: Vec<(String, Arc<Base>)>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
@@ -31,44 +46,277 @@ pub enum Change {
 
     /// A "fsync" operation.
     Sync,
+
+    /// A "fdatasync" operation.
+    DataSync,
+
+    /// A "flush" operation. Issued on each close() of the file.
+    Flush,
+
+    /// A "release" operation. Issued when the last reference to the opened
+    /// file goes away.
+    Release,
+
+    /// A "write" operation with "forced unit access" semantics. The data is
+    /// persisted once the write completes, without waiting for a Sync.
+    FuaWrite {
+        offset: usize,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+
+    /// A "discard" (TRIM, or punch hole) operation. Content of the range
+    /// becomes unspecified. See `DiscardMode`.
+    Discard { offset: usize, len: usize },
+
+    /// A "truncate" operation that changes the file length.
+    Resize { len: usize },
+
+    /// A change to a file in multi-file mode.
+    File { file: usize, change: Box<Change> },
+}
+
+/// Decide what discarded ranges read back as when replaying changes.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DiscardMode {
+    /// Read back as zeros.
+    #[default]
+    Zero,
+
+    /// Read back as the old data.
+    Keep,
+
+    /// Read back as garbage.
+    Garbage,
 }
 
 /// Describe what changes to take and what to skip.
+///
+/// Taken changes are replayed in order, except for `deferred` ones, which are
+/// replayed last. Writes between two barriers could be in flight concurrently
+/// and persisted in any order, which `deferred` emulates. A "crash at N"
+/// point truncates `should_take` so changes since N are not taken.
 #[derive(Debug)]
 pub struct ChangeFilter {
     should_take: Vec<bool>,
+
+    /// Whether to take changes not covered by `should_take`.
+    take_rest: bool,
+
+    deferred: Vec<usize>,
+}
+
+impl Change {
+    /// Test if the change is a "sync"-like operation (fsync, fdatasync,
+    /// flush, release).
+    pub fn is_sync(&self) -> bool {
+        match self {
+            Change::Sync | Change::DataSync | Change::Flush | Change::Release => true,
+            Change::Write { .. }
+            | Change::FuaWrite { .. }
+            | Change::Discard { .. }
+            | Change::Resize { .. } => false,
+            Change::File { change, .. } => change.is_sync(),
+        }
+    }
+
+    /// Return the offset and data if the change is a write.
+    pub fn as_write(&self) -> Option<(usize, &[u8])> {
+        match self {
+            Change::Write { offset, data } | Change::FuaWrite { offset, data } => {
+                Some((*offset, data))
+            }
+            Change::File { change, .. } => change.as_write(),
+            _ => None,
+        }
+    }
+
+    /// Name of the change, for example, "Write" or "Sync".
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Change::Write { .. } => "Write",
+            Change::Sync => "Sync",
+            Change::DataSync => "DataSync",
+            Change::Flush => "Flush",
+            Change::Release => "Release",
+            Change::FuaWrite { .. } => "FuaWrite",
+            Change::Discard { .. } => "Discard",
+            Change::Resize { .. } => "Resize",
+            Change::File { change, .. } => change.kind(),
+        }
+    }
+
+    /// Return the file index (in multi-file mode) and the actual change.
+    pub fn split_file(&self) -> (Option<usize>, &Change) {
+        match self {
+            Change::File { file, change } => (Some(*file), change),
+            _ => (None, self),
+        }
+    }
+
+    /// Tag the change with a file index in multi-file mode.
+    pub fn with_file(self, file: Option<usize>) -> Change {
+        match file {
+            Some(file) => Change::File {
+                file,
+                change: Box::new(self),
+            },
+            None => self,
+        }
+    }
 }
 
 impl Journal {
     /// Create `Journal` using specified initial data.
     pub fn new(data: impl Into<Vec<u8>>) -> Self {
         Self {
-            initial_data: Rc::new(data.into()),
+            initial_data: Arc::new(Base::from(data.into())),
             changes: Vec::new(),
+            discard_mode: DiscardMode::default(),
+            files: Vec::new(),
         }
     }
 
+    /// Create `Journal` in multi-file mode using specified file names and
+    /// initial data.
+    pub fn with_files(files: Vec<(String, Vec<u8>)>) -> Self {
+        Self {
+            initial_data: Arc::new(Base::from(Vec::new())),
+            changes: Vec::new(),
+            discard_mode: DiscardMode::default(),
+            files: files
+                .into_iter()
+                .map(|(name, data)| (name, Arc::new(Base::from(data))))
+                .collect(),
+        }
+    }
+
+    /// Test if the journal is in multi-file mode.
+    pub fn is_multi_file(&self) -> bool {
+        !self.files.is_empty()
+    }
+
     /// Return data with changes applied.
     pub fn data(&self, filter: Option<&ChangeFilter>) -> Vec<u8> {
+        self.image(filter).to_vec()
+    }
+
+    /// Return data of the specified file with changes applied, in multi-file
+    /// mode.
+    pub fn file_data(&self, file: usize, filter: Option<&ChangeFilter>) -> Vec<u8> {
+        self.file_image(file, filter).to_vec()
+    }
+
+    /// Return the image with changes applied, without copying unchanged
+    /// pages of the initial data.
+    pub fn image(&self, filter: Option<&ChangeFilter>) -> Image {
+        self.apply(&self.initial_data, None, filter)
+    }
+
+    /// Return the image of the specified file with changes applied, in
+    /// multi-file mode.
+    pub fn file_image(&self, file: usize, filter: Option<&ChangeFilter>) -> Image {
+        self.apply(&self.files[file].1, Some(file), filter)
+    }
+
+    /// Return images of all files (or the single image in single-file mode)
+    /// with changes applied.
+    pub fn images(&self, filter: Option<&ChangeFilter>) -> Vec<Image> {
+        let mut images = self.initial_images();
+        self.apply_all(&mut images, filter, 0..self.changes.len());
+        images
+    }
+
+    /// Names of files in multi-file mode. Empty in single-file mode.
+    pub fn file_names(&self) -> Vec<String> {
+        self.files.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Return images of all files (or the single image in single-file mode)
+    /// without changes applied.
+    fn initial_images(&self) -> Vec<Image> {
+        if self.is_multi_file() {
+            let files = self.files.iter();
+            files.map(|(_, data)| Image::new(data.clone())).collect()
+        } else {
+            vec![Image::new(self.initial_data.clone())]
+        }
+    }
+
+    /// Apply changes with indexes in `range` to images of all files.
+    fn apply_all(&self, images: &mut [Image], filter: Option<&ChangeFilter>, range: Range<usize>) {
+        if self.is_multi_file() {
+            for (i, image) in images.iter_mut().enumerate() {
+                self.apply_range(image, Some(i), filter, range.clone());
+            }
+        } else {
+            self.apply_range(&mut images[0], None, filter, range);
+        }
+    }
+
+    /// Apply changes of the specified file to the initial data.
+    fn apply(
+        &self,
+        initial: &Arc<Base>,
+        file: Option<usize>,
+        filter: Option<&ChangeFilter>,
+    ) -> Image {
+        let mut image = Image::new(initial.clone());
+        self.apply_range(&mut image, file, filter, 0..self.changes.len());
+        image
+    }
+
+    /// Apply changes of the specified file with indexes in `range` to `data`.
+    pub fn apply_range(
+        &self,
+        data: &mut Image,
+        file: Option<usize>,
+        filter: Option<&ChangeFilter>,
+        range: Range<usize>,
+    ) {
         // Apply chanes
-        let mut data = Vec::clone(&self.initial_data);
-        for (i, change) in self.changes.iter().enumerate() {
-            if let Some(filter) = filter {
-                if filter.should_take.get(i) != Some(&true) {
-                    continue;
-                }
+        let order = match filter {
+            Some(filter) => filter.replay_order(self.changes.len()),
+            None => range.clone().collect(),
+        };
+        for i in order {
+            if !range.contains(&i) {
+                continue;
             }
-            if let Change::Write { offset, data: b } = &change {
-                data[*offset..*offset + b.len()].copy_from_slice(&b);
+            let (change_file, change) = self.changes[i].split_file();
+            if change_file != file {
+                continue;
+            }
+            match change {
+                // Writes past the end grow the data.
+                Change::Write { offset, data: b } | Change::FuaWrite { offset, data: b } => {
+                    data.write(*offset, b)
+                }
+                Change::Discard { offset, len } => {
+                    let end = (offset + len).min(data.len());
+                    let offset = (*offset).min(end);
+                    let mode = self.discard_mode;
+                    if mode != DiscardMode::Keep {
+                        data.modify(offset..end, |buf, offset| mode.apply(buf, offset));
+                    }
+                }
+                Change::Resize { len } => data.set_len(*len),
+                _ => {}
             }
         }
-        data
     }
 
     /// Dump state to a directory.
+    ///
+    /// In multi-file mode, `base_path` is a directory containing the files.
     pub fn dump(&self, base_path: &Path, changes_path: &Path) -> io::Result<()> {
-        if fs::read(base_path).ok().as_ref() != Some(&*self.initial_data) {
-            fs::write(base_path, &*self.initial_data).context(base_path.display())?;
+        if self.is_multi_file() {
+            fs::create_dir_all(base_path).context(base_path.display())?;
+            for (name, data) in &self.files {
+                write_base(&base_path.join(name), data)?;
+            }
+        } else {
+            write_base(base_path, &self.initial_data)?;
         }
         if !self.changes.is_empty() || changes_path.exists() {
             fs::write(changes_path, varbincode::serialize(&self.changes).unwrap())
@@ -78,8 +326,28 @@ impl Journal {
     }
 
     /// Load state from a directory.
+    ///
+    /// If `base_path` is a directory, load in multi-file mode. Files are
+    /// sorted by name.
+    ///
+    /// Base files are mapped instead of read. They must not be truncated or
+    /// modified by other processes while the journal is alive. See `Base`.
     pub fn load(base_path: &Path, changes_path: &Path) -> io::Result<Self> {
-        let init = fs::read(base_path).context(&base_path.display())?;
+        let mut files = Vec::new();
+        let init = if base_path.is_dir() {
+            for entry in fs::read_dir(base_path).context(base_path.display())? {
+                let path = entry?.path();
+                if path.is_file() {
+                    let name = path.file_name().unwrap().to_string_lossy().to_string();
+                    let data = Base::open(&path).context(path.display())?;
+                    files.push((name, Arc::new(data)));
+                }
+            }
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            Base::from(Vec::new())
+        } else {
+            Base::open(base_path).context(&base_path.display())?
+        };
         let changes: Vec<Change> = if changes_path.exists() {
             let data = fs::read(changes_path)?;
             varbincode::deserialize(&data[..])
@@ -87,15 +355,32 @@ impl Journal {
         } else {
             Vec::new()
         };
+        // Changes are tagged with file indexes only in multi-file mode.
+        for change in &changes {
+            let (file, change) = change.split_file();
+            let error = match (file, change) {
+                (_, Change::File { .. }) => "nested File change",
+                (None, _) if !files.is_empty() => "untagged change in multi-file mode",
+                (Some(_), _) if files.is_empty() => "File change in single-file mode",
+                (Some(i), _) if i >= files.len() => "change to unknown file",
+                _ => continue,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error))
+                .context(changes_path.display());
+        }
         Ok(Self {
-            initial_data: Rc::new(init),
+            initial_data: Arc::new(init),
             changes,
+            discard_mode: DiscardMode::default(),
+            files,
         })
     }
 
-    /// Mount to the destination path as a single, fixed-sized file.
+    /// Mount to the destination path as a single file.
+    ///
+    /// In multi-file mode, mount as a directory of files instead.
     ///
-    /// Changes to that file are recorded in this journal.
+    /// Changes to files are recorded in this journal.
     ///
     /// When the returned value gets dropped, umount the filesystem.
     pub fn mount(
@@ -104,30 +389,270 @@ impl Journal {
         opts: &[String],
         filter: Option<&ChangeFilter>,
     ) -> io::Result<fuse::BackgroundSession> {
-        let data = self.data(filter);
-        let fs = crate::fs::FuseOutageFilesystem::new(data, &mut self.changes);
-        // Add '-o allow_root' automatically.
-        let uid = unsafe { libc::getuid() };
-        let fixed_opts = if opts.contains(&"allow_other".to_string()) || uid == 0 {
-            vec![]
-        } else {
-            vec!["-o".to_string(), "allow_root".to_string()]
+        let images = self.images(filter);
+        mount_images(self.file_names(), images, &mut self.changes, dest, opts)
+    }
+
+    /// Serve the image over NBD at the given unix socket.
+    ///
+    /// Requests to the block device are recorded in this journal.
+    ///
+    /// When the returned value gets dropped, stop the server.
+    pub fn serve_nbd(
+        &mut self,
+        socket_path: &Path,
+        filter: Option<&ChangeFilter>,
+    ) -> io::Result<crate::nbd::BackgroundServer> {
+        if self.is_multi_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "nbd does not support multi-file mode",
+            ));
+        }
+        let data = self.image(filter);
+        crate::nbd::NbdServer::new(data, &mut self.changes).spawn(socket_path)
+    }
+}
+
+/// Write initial data to `path`, unless the file already has the data.
+///
+/// The file is not read into memory. It is not written if `data` is a mapping
+/// of it, since truncating a mapped file breaks the mapping.
+fn write_base(path: &Path, data: &Base) -> io::Result<()> {
+    if data.is_mapping_of(path) || has_content(path, data).unwrap_or(false) {
+        return Ok(());
+    }
+    fs::write(path, &data[..]).context(path.display())
+}
+
+/// Test if the file at `path` has the given content, reading it in chunks.
+fn has_content(path: &Path, data: &[u8]) -> io::Result<bool> {
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() != data.len() as u64 {
+        return Ok(false);
+    }
+    let mut buf = vec![0; 1 << 20];
+    for chunk in data.chunks(buf.len()) {
+        let buf = &mut buf[..chunk.len()];
+        file.read_exact(buf)?;
+        if buf != chunk {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Mount images to the destination path as a single file, or as a directory of
+/// files with the given names if `names` is not empty.
+///
+/// Changes to files are recorded in `changes`.
+///
+/// When the returned value gets dropped, umount the filesystem.
+pub fn mount_images<'a>(
+    names: Vec<String>,
+    images: Vec<Image>,
+    changes: &'a mut Vec<Change>,
+    dest: &Path,
+    opts: &[String],
+) -> io::Result<fuse::BackgroundSession<'a>> {
+    let fs = if names.is_empty() {
+        let data = images.into_iter().next().unwrap();
+        crate::fs::FuseOutageFilesystem::new(data, changes)
+    } else {
+        let files = names.into_iter().zip(images).collect();
+        crate::fs::FuseOutageFilesystem::with_files(files, changes)
+    };
+    // Add '-o allow_root' automatically.
+    let uid = unsafe { libc::getuid() };
+    let fixed_opts = if opts.contains(&"allow_other".to_string()) || uid == 0 {
+        vec![]
+    } else {
+        vec!["-o".to_string(), "allow_root".to_string()]
+    };
+    let opts: Vec<&OsStr> = fixed_opts
+        .iter()
+        .chain(opts.iter())
+        .map(|s| OsStr::new(s))
+        .collect();
+    debug!("fuse mount options: {:?}", &opts);
+    unsafe { fuse::spawn_mount(fs, dest, &opts) }
+}
+
+/// Replay changes for many filters, reusing images at `Sync` boundaries.
+///
+/// Test cases usually take all changes before a barrier and differ only
+/// after it. Images with all changes before a `Sync` taken are cached as
+/// checkpoints, so only changes since the checkpoint are applied for each
+/// filter.
+pub struct Replayer<'a> {
+    journal: &'a Journal,
+
+    /// Images of all files, with changes before the index applied.
+    checkpoints: BTreeMap<usize, Vec<Image>>,
+}
+
+impl<'a> Replayer<'a> {
+    pub fn new(journal: &'a Journal) -> Self {
+        let mut checkpoints = BTreeMap::new();
+        checkpoints.insert(0, journal.initial_images());
+        Self {
+            journal,
+            checkpoints,
+        }
+    }
+
+    /// Return images of all files (or the single image in single-file mode)
+    /// with changes applied. Same as `Journal::images`.
+    pub fn images(&mut self, filter: Option<&ChangeFilter>) -> Vec<Image> {
+        let len = self.journal.changes.len();
+        // Changes before `prefix_len` are taken in order.
+        let prefix_len = match filter {
+            None => len,
+            Some(filter) => (0..len)
+                .find(|i| !filter.should_take(*i) || filter.deferred().contains(i))
+                .unwrap_or(len),
         };
-        let opts: Vec<&OsStr> = fixed_opts
-            .iter()
-            .chain(opts.iter())
-            .map(|s| OsStr::new(s))
+        let (start, mut images) = self.checkpoint(prefix_len);
+        self.journal.apply_all(&mut images, filter, start..len);
+        images
+    }
+
+    /// Return the index and images of the last checkpoint at a `Sync` before
+    /// `end`. Images returned by `images` for filters that take all changes
+    /// before `end` share unchanged pages with them.
+    pub fn checkpoint(&mut self, end: usize) -> (usize, Vec<Image>) {
+        let index = self.checkpoint_index(end);
+        (index, self.checkpoints[&index].clone())
+    }
+
+    /// Find or create the last checkpoint at a `Sync` before `end`.
+    /// Return its index.
+    fn checkpoint_index(&mut self, end: usize) -> usize {
+        let changes = &self.journal.changes;
+        let index = (1..end.min(changes.len()))
+            .rev()
+            .find(|&i| changes[i].is_sync())
+            .unwrap_or(0);
+        if !self.checkpoints.contains_key(&index) {
+            let (&start, images) = self.checkpoints.range(..index).next_back().unwrap();
+            let mut images = images.clone();
+            self.journal.apply_all(&mut images, None, start..index);
+            self.checkpoints.insert(index, images);
+        }
+        index
+    }
+}
+
+impl DiscardMode {
+    /// Replace `data` (starting at `offset` of the file) as discarded.
+    fn apply(self, data: &mut [u8], offset: usize) {
+        match self {
+            DiscardMode::Zero => data.iter_mut().for_each(|b| *b = 0),
+            DiscardMode::Keep => {}
+            DiscardMode::Garbage => {
+                // Deterministic so test cases are reproducible.
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = ((offset + i) as u8).wrapping_mul(0x9d) ^ 0x5a;
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for DiscardMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "zero" => Ok(DiscardMode::Zero),
+            "keep" => Ok(DiscardMode::Keep),
+            "garbage" => Ok(DiscardMode::Garbage),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown discard mode: {}", s),
+            )),
+        }
+    }
+}
+
+impl ChangeFilter {
+    /// Test if the `index`-th change should be taken.
+    pub fn should_take(&self, index: usize) -> bool {
+        self.should_take
+            .get(index)
+            .cloned()
+            .unwrap_or(self.take_rest)
+    }
+
+    /// Index of the first change after which no change is taken, for
+    /// example, the crash point. `None` if all later changes are taken.
+    pub fn end(&self) -> Option<usize> {
+        if self.take_rest {
+            None
+        } else {
+            Some(self.should_take.len())
+        }
+    }
+
+    /// Indexes of changes that are replayed last.
+    pub fn deferred(&self) -> &[usize] {
+        &self.deferred
+    }
+
+    /// Indexes of changes to replay, in replay order.
+    pub fn replay_order(&self, len: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..len)
+            .filter(|i| self.should_take(*i) && !self.deferred.contains(i))
             .collect();
-        debug!("fuse mount options: {:?}", &opts);
-        return unsafe { fuse::spawn_mount(fs, dest, &opts) };
+        order.extend(
+            self.deferred
+                .iter()
+                .filter(|&&i| i < len && self.should_take(i)),
+        );
+        order
     }
 }
 
+/// Maximum number of changes a filter can refer to.
+const MAX_FILTER_LEN: usize = u32::MAX as usize;
+
 impl FromStr for ChangeFilter {
     type Err = io::Error;
 
     fn from_str(s: &str) -> io::Result<Self> {
         let mut result = Vec::new();
+        let mut deferred = Vec::new();
+        let mut crash_at = None;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "filter is too large");
+        // Add `b` to `a`, within `MAX_FILTER_LEN`.
+        let add = |a: usize, b: usize| -> io::Result<usize> {
+            match a.checked_add(b) {
+                Some(sum) if sum <= MAX_FILTER_LEN => Ok(sum),
+                _ => Err(too_large()),
+            }
+        };
+        let parse_index = |s: &str| -> io::Result<usize> {
+            let index = s
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            add(index, 0)
+        };
+        // Suffixes: "/" (deferred changes) and "@" (crash point).
+        let is_suffix = |c: char| c == '/' || c == '@';
+        let (s, mut suffix) = s.split_at(s.find(is_suffix).unwrap_or(s.len()));
+        while !suffix.is_empty() {
+            let end = suffix[1..].find(is_suffix).map_or(suffix.len(), |p| p + 1);
+            let (part, rest) = suffix.split_at(end);
+            let (kind, value) = part.split_at(1);
+            if kind == "@" {
+                crash_at = Some(parse_index(value)?);
+            } else {
+                for index in value.split(',') {
+                    deferred.push(parse_index(index)?);
+                }
+            }
+            suffix = rest;
+        }
         let push_bitvec = |result: &mut Vec<bool>, bitvec: &str| -> io::Result<()> {
             for ch in bitvec.chars() {
                 match ch {
@@ -144,22 +669,66 @@ impl FromStr for ChangeFilter {
             Ok(())
         };
 
-        if s.contains(":") {
+        let (negate, s) = match s.strip_prefix('^') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        if let Some(runs) = s.strip_prefix("rle:") {
+            // Alternating runs of taken and skipped changes.
+            for (i, run) in runs.split(',').enumerate() {
+                let len = add(result.len(), parse_index(run)?)?;
+                result.resize(len, i % 2 == 0);
+            }
+        } else if s.contains(":") {
             let mut split = s.splitn(2, ":");
             let start_from = split.next().unwrap();
             let bitvec = split.next().unwrap();
-            let start_from = start_from
-                .parse()
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-            for _ in 0..start_from {
-                result.push(true);
-            }
+            let start_from = parse_index(start_from)?;
+            add(start_from, bitvec.len())?;
+            result.resize(start_from, true);
             push_bitvec(&mut result, &bitvec)?;
-        } else {
+        } else if !negate && !s.contains(&[',', '-'][..]) {
+            // Without "," or "-", it is not a list of indexes.
+            if !s.chars().all(|c| c == '0' || c == '1') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ambiguous filter: use \"{},\" for a single index", s),
+                ));
+            }
             push_bitvec(&mut result, &s)?;
+        } else {
+            // Indexes or ranges of taken changes. After "^", they are always
+            // indexes, not bits.
+            for item in s.split(',').filter(|s| !s.is_empty()) {
+                let (start, end) = match item.find('-') {
+                    Some(pos) => (parse_index(&item[..pos])?, parse_index(&item[pos + 1..])?),
+                    None => (parse_index(item)?, parse_index(item)?),
+                };
+                if start > end {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid range: {}", item),
+                    ));
+                }
+                if result.len() <= end {
+                    result.resize(add(end, 1)?, false);
+                }
+                result[start..=end].iter_mut().for_each(|b| *b = true);
+            }
+        }
+        let mut take_rest = false;
+        if negate {
+            result.iter_mut().for_each(|b| *b = !*b);
+            take_rest = true;
+        }
+        if let Some(index) = crash_at {
+            result.resize(index, take_rest);
+            take_rest = false;
         }
         Ok(Self {
             should_take: result,
+            take_rest,
+            deferred,
         })
     }
 }
@@ -187,6 +756,96 @@ mod tests {
         assert_eq!(journal.data(None), vec![8, 3, 6]);
     }
 
+    #[test]
+    fn test_journal_fua_write() {
+        let mut journal = Journal::new(vec![9, 5, 7]);
+        journal.changes.push(Change::FuaWrite {
+            offset: 1,
+            data: vec![4, 6],
+        });
+        journal.changes.push(Change::Sync);
+        journal.changes.push(Change::Write {
+            offset: 0,
+            data: vec![8],
+        });
+        assert_eq!(journal.data(None), vec![8, 4, 6]);
+        assert_eq!(journal.changes[0].as_write(), Some((1, &[4, 6][..])));
+        assert_eq!(journal.changes[1].as_write(), None);
+    }
+
+    #[test]
+    fn test_journal_discard() {
+        let mut journal = Journal::new(vec![9, 5, 7, 3]);
+        journal.changes.push(Change::Discard { offset: 1, len: 2 });
+        assert_eq!(journal.data(None), vec![9, 0, 0, 3]);
+        journal.discard_mode = DiscardMode::Keep;
+        assert_eq!(journal.data(None), vec![9, 5, 7, 3]);
+        journal.discard_mode = DiscardMode::Garbage;
+        let data = journal.data(None);
+        assert_eq!((data[0], data[3]), (9, 3));
+        assert_ne!(&data[1..3], &[5, 7]);
+        assert_eq!(journal.data(None), data);
+    }
+
+    #[test]
+    fn test_journal_resize() {
+        let mut journal = Journal::new(vec![9, 5, 7]);
+        journal.changes.push(Change::Write {
+            offset: 2,
+            data: vec![4, 6],
+        });
+        assert_eq!(journal.data(None), vec![9, 5, 4, 6]);
+        journal.changes.push(Change::Resize { len: 2 });
+        assert_eq!(journal.data(None), vec![9, 5]);
+        journal.changes.push(Change::Resize { len: 5 });
+        assert_eq!(journal.data(None), vec![9, 5, 0, 0, 0]);
+        journal.changes.push(Change::Discard { offset: 4, len: 3 });
+        assert_eq!(journal.data(None), vec![9, 5, 0, 0, 0]);
+
+        let filter: ChangeFilter = "001".parse().unwrap();
+        assert_eq!(journal.data(Some(&filter)), vec![9, 5, 7, 0, 0]);
+    }
+
+    #[test]
+    fn test_journal_multi_file() {
+        let mut journal = Journal::with_files(vec![
+            ("a".to_string(), vec![9, 5, 7]),
+            ("b".to_string(), vec![3, 2]),
+        ]);
+        journal.changes.push(
+            Change::Write {
+                offset: 0,
+                data: vec![1],
+            }
+            .with_file(Some(1)),
+        );
+        journal.changes.push(Change::Sync.with_file(Some(1)));
+        journal.changes.push(
+            Change::Write {
+                offset: 1,
+                data: vec![4, 6],
+            }
+            .with_file(Some(0)),
+        );
+        assert_eq!(journal.file_data(0, None), vec![9, 4, 6]);
+        assert_eq!(journal.file_data(1, None), vec![1, 2]);
+        assert!(journal.changes[1].is_sync());
+        assert_eq!(journal.changes[2].split_file().0, Some(0));
+
+        let filter: ChangeFilter = "01".parse().unwrap();
+        assert_eq!(journal.file_data(0, Some(&filter)), vec![9, 5, 7]);
+        assert_eq!(journal.file_data(1, Some(&filter)), vec![3, 2]);
+
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("base");
+        let changes_path = dir.path().join("changes");
+        journal.dump(&base_path, &changes_path).unwrap();
+        assert_eq!(fs::read(base_path.join("b")).unwrap(), vec![3, 2]);
+        let journal2 = Journal::load(&base_path, &changes_path).unwrap();
+        assert_eq!(journal2.files, journal.files);
+        assert_eq!(journal2.changes, journal.changes);
+    }
+
     #[test]
     fn test_journal_change_filter() {
         let mut journal = Journal::new(vec![9, 5, 7]);
@@ -209,6 +868,113 @@ mod tests {
         assert_eq!(journal.data(p("01").as_ref()), vec![8, 3, 7]);
         assert_eq!(journal.data(p("00").as_ref()), vec![9, 5, 7]);
         assert_eq!(journal.data(p("2:0").as_ref()), vec![8, 3, 6]);
+        // Reorder: the first write lands after the second one.
+        assert_eq!(journal.data(p("11/0").as_ref()), vec![8, 4, 6]);
+        assert_eq!(journal.data(p("01/0").as_ref()), vec![8, 3, 7]);
+        assert_eq!(journal.data(p("2:/1,0").as_ref()), vec![8, 4, 6]);
+        // Crash at a change: later changes are not taken.
+        assert_eq!(journal.data(p("11@1").as_ref()), vec![9, 4, 6]);
+        assert_eq!(journal.data(p("2:@0").as_ref()), vec![9, 5, 7]);
+        assert_eq!(journal.data(p("11/0@2").as_ref()), vec![8, 4, 6]);
+        assert_eq!(journal.data(p("11@2/0").as_ref()), vec![8, 4, 6]);
+        // Indexes, ranges, run-length and negation.
+        assert_eq!(journal.data(p("0-1").as_ref()), vec![8, 3, 6]);
+        assert_eq!(journal.data(p("1,").as_ref()), vec![8, 3, 7]);
+        assert_eq!(journal.data(p("rle:0,1,1").as_ref()), vec![8, 3, 7]);
+        assert_eq!(journal.data(p("rle:1,1").as_ref()), vec![9, 4, 6]);
+        assert_eq!(journal.data(p("^0").as_ref()), vec![8, 3, 7]);
+        assert_eq!(journal.data(p("^1,").as_ref()), vec![9, 4, 6]);
+        assert_eq!(journal.data(p("^0@1").as_ref()), vec![9, 5, 7]);
+        assert_eq!(p("^0").unwrap().end(), None);
+        assert_eq!(p("^0@1").unwrap().end(), Some(1));
+        assert_eq!(p("2:01").unwrap().end(), Some(4));
+        assert_eq!(p("11@5/0").unwrap().end(), Some(5));
+        assert!("1-x".parse::<ChangeFilter>().is_err());
+        assert!("5-1".parse::<ChangeFilter>().is_err());
+        assert!("^3-2".parse::<ChangeFilter>().is_err());
+        // Indexes need "," or "-" so they are not read as bits.
+        assert_eq!(
+            journal.data(p("10").as_ref()),
+            journal.data(p("0,").as_ref())
+        );
+        assert!("5".parse::<ChangeFilter>().is_err());
+        assert!("5,".parse::<ChangeFilter>().is_ok());
+        // Filters past the limit are rejected without allocating.
+        for s in &[
+            "0-18446744073709551615",
+            "0-4294967296",
+            "rle:1,4294967295",
+            "1@4294967296",
+            "/4294967296",
+            "4294967296:1",
+        ] {
+            let error = s.parse::<ChangeFilter>().err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_replayer() {
+        let mut journal = Journal::with_files(vec![
+            ("a".to_string(), vec![9, 5, 7]),
+            ("b".to_string(), vec![3, 2]),
+        ]);
+        for i in 0..6 {
+            let file = Some(i % 2);
+            let data = vec![i as u8; i + 1];
+            journal
+                .changes
+                .push(Change::Write { offset: i, data }.with_file(file));
+            if i % 3 == 1 {
+                journal.changes.push(Change::Sync.with_file(file));
+            }
+        }
+        let mut replayer = Replayer::new(&journal);
+        let filters = [
+            "",
+            "0",
+            "1",
+            "11",
+            "111",
+            "1110",
+            "11101",
+            "111111",
+            "1111110",
+            "11:0",
+            "11:1",
+            "^2",
+            "^9",
+            "111/0",
+            "11111/3,4",
+            "1111@5",
+            "rle:4,1,2",
+        ];
+        for filter in filters.iter() {
+            let filter: ChangeFilter = filter.parse().unwrap();
+            let images = replayer.images(Some(&filter));
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].to_vec(), journal.file_data(0, Some(&filter)));
+            assert_eq!(images[1].to_vec(), journal.file_data(1, Some(&filter)));
+        }
+        let images = replayer.images(None);
+        assert_eq!(images[0].to_vec(), journal.file_data(0, None));
+        // Checkpoints are created at syncs.
+        let checkpoints: Vec<usize> = replayer.checkpoints.keys().cloned().collect();
+        assert_eq!(checkpoints, vec![0, 2, 6]);
+
+        let mut journal = Journal::new(vec![9, 5, 7]);
+        journal.changes.push(Change::Write {
+            offset: 1,
+            data: vec![4, 6],
+        });
+        journal.changes.push(Change::Sync);
+        journal.changes.push(Change::Resize { len: 1 });
+        let mut replayer = Replayer::new(&journal);
+        for filter in &["111", "110", "011", "1"] {
+            let filter: ChangeFilter = filter.parse().unwrap();
+            let images = replayer.images(Some(&filter));
+            assert_eq!(images[0].to_vec(), journal.data(Some(&filter)));
+        }
     }
 
     #[test]
@@ -246,6 +1012,38 @@ mod tests {
         file.write_all(&data).unwrap();
     }
 
+    #[test]
+    fn test_load_old_format() {
+        // A changes file written before changes other than Write and Sync
+        // existed. Existing variants must keep their indexes.
+        const OLD_CHANGES: [u8; 34] = [
+            2, 0, 0, 0, 0, 0, 0, 0, // 2 changes
+            0, 0, 0, 0, // Write
+            1, 0, 0, 0, 0, 0, 0, 0, // offset
+            2, 0, 0, 0, 0, 0, 0, 0, 4, 6, // data
+            1, 0, 0, 0, // Sync
+        ];
+        let changes = vec![
+            Change::Write {
+                offset: 1,
+                data: vec![4, 6],
+            },
+            Change::Sync,
+        ];
+        let loaded: Vec<Change> = varbincode::deserialize(&OLD_CHANGES).unwrap();
+        assert_eq!(loaded, changes);
+        assert_eq!(varbincode::serialize(&changes).unwrap(), OLD_CHANGES);
+
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("base");
+        let changes_path = dir.path().join("changes");
+        fs::write(&base_path, [9, 5, 7]).unwrap();
+        fs::write(&changes_path, OLD_CHANGES).unwrap();
+        let journal = Journal::load(&base_path, &changes_path).unwrap();
+        assert_eq!(journal.changes, changes);
+        assert_eq!(journal.data(None), vec![9, 4, 6]);
+    }
+
     #[test]
     fn test_dump_load() {
         let dir = tempdir().unwrap();
@@ -267,5 +1065,44 @@ mod tests {
         let journal2 = Journal::load(&base_path, &changes_path).unwrap();
         assert_eq!(journal2.changes, journal.changes);
         assert_eq!(journal2.data(None), journal.data(None));
+
+        // The base is not rewritten if it has the data.
+        assert!(has_content(&base_path, &[9, 5, 7]).unwrap());
+        assert!(!has_content(&base_path, &[9, 5, 8]).unwrap());
+        let mtime = || fs::metadata(&base_path).unwrap().modified().unwrap();
+        let old_mtime = mtime();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        journal2.dump(&base_path, &changes_path).unwrap();
+        journal.dump(&base_path, &changes_path).unwrap();
+        assert_eq!(mtime(), old_mtime);
+
+        // File changes cannot be nested, or used in single-file mode.
+        let in_file = |file: usize, change: Change| Change::File {
+            file,
+            change: Box::new(change),
+        };
+        for change in [
+            in_file(0, in_file(0, Change::Sync)),
+            in_file(0, Change::Sync),
+            in_file(1, Change::Sync),
+        ] {
+            journal.changes = vec![change];
+            journal.dump(&base_path, &changes_path).unwrap();
+            let error = Journal::load(&base_path, &changes_path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // In multi-file mode, changes must be tagged with existing files.
+        let base_path = dir.path().join("files");
+        let mut journal = Journal::with_files(vec![("a".to_string(), vec![1])]);
+        journal.changes = vec![in_file(0, Change::Sync)];
+        journal.dump(&base_path, &changes_path).unwrap();
+        Journal::load(&base_path, &changes_path).unwrap();
+        for change in [Change::Sync, in_file(1, Change::Sync)] {
+            journal.changes = vec![change];
+            journal.dump(&base_path, &changes_path).unwrap();
+            let error = Journal::load(&base_path, &changes_path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
     }
 }
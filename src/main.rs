@@ -1,7 +1,11 @@
 pub mod cli;
 pub mod errors;
 pub mod fs;
+pub mod image;
 pub mod journal;
+pub mod nbd;
+pub mod report;
+pub mod tree;
 pub mod vendor;
 
 fn main() {
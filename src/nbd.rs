@@ -0,0 +1,437 @@
+use crate::errors::Context;
+use crate::image::Image;
+use crate::journal::Change;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::BE;
+use log::debug;
+use log::info;
+use log::warn;
+use std::convert::TryFrom;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thread_scoped::JoinGuard;
+
+// See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md.
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const REPLY_MAGIC: u64 = 0x3e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_INFO_EXPORT: u16 = 0;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+/// Maximum length of option data sent by the client during handshake.
+const MAX_OPTION_LEN: u32 = 4096;
+
+/// NBD server state for "outagefs" - a single block device recording write,
+/// flush and trim requests.
+pub struct NbdServer<'a> {
+    /// Content of the block device.
+    data: Image,
+
+    /// Modifications to the block device.
+    changes: &'a mut Vec<Change>,
+}
+
+/// NBD server running in a background thread.
+///
+/// When dropped, disconnect the client and stop the server.
+pub struct BackgroundServer<'a> {
+    socket_path: PathBuf,
+    state: Arc<Mutex<ServerState>>,
+    guard: Option<JoinGuard<'a, io::Result<()>>>,
+}
+
+#[derive(Default)]
+struct ServerState {
+    stopped: bool,
+
+    /// The connection being served.
+    stream: Option<UnixStream>,
+}
+
+/// Request sent by the client during transmission.
+struct NbdRequest {
+    flags: u16,
+    kind: u16,
+    handle: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl<'a> NbdServer<'a> {
+    pub fn new(data: impl Into<Image>, changes: &'a mut Vec<Change>) -> Self {
+        Self {
+            data: data.into(),
+            changes,
+        }
+    }
+
+    /// Serve clients at the given unix socket in a background thread.
+    pub fn spawn(self, socket_path: &Path) -> io::Result<BackgroundServer<'a>> {
+        if socket_path.exists() {
+            std::fs::remove_file(socket_path).context(socket_path.display())?;
+        }
+        let listener = UnixListener::bind(socket_path).context(socket_path.display())?;
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let thread_state = state.clone();
+        let guard = unsafe { thread_scoped::scoped(move || self.run(listener, thread_state)) };
+        Ok(BackgroundServer {
+            socket_path: socket_path.to_path_buf(),
+            state,
+            guard: Some(guard),
+        })
+    }
+
+    /// Accept and serve clients one by one until stopped.
+    fn run(mut self, listener: UnixListener, state: Arc<Mutex<ServerState>>) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            {
+                let mut state = state.lock().unwrap();
+                if state.stopped {
+                    break;
+                }
+                state.stream = Some(stream.try_clone()?);
+            }
+            info!("nbd client connected");
+            if let Err(e) = self.serve(stream) {
+                if !state.lock().unwrap().stopped {
+                    warn!("nbd client error: {}", e);
+                }
+            }
+            info!("nbd client disconnected");
+            state.lock().unwrap().stream = None;
+        }
+        Ok(())
+    }
+
+    /// Serve a client connection.
+    fn serve(&mut self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = stream.try_clone()?;
+        let mut writer = BufWriter::new(stream);
+        if self.handshake(&mut reader, &mut writer)? {
+            self.transmission(&mut reader, &mut writer)?;
+        }
+        Ok(())
+    }
+
+    /// Negotiate using the "fixed newstyle" handshake.
+    ///
+    /// Return `true` if the client enters the transmission phase.
+    fn handshake(&self, reader: &mut impl Read, writer: &mut impl Write) -> io::Result<bool> {
+        writer.write_u64::<BE>(NBDMAGIC)?;
+        writer.write_u64::<BE>(IHAVEOPT)?;
+        writer.write_u16::<BE>(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES)?;
+        writer.flush()?;
+        let client_flags = reader.read_u32::<BE>()?;
+
+        loop {
+            if reader.read_u64::<BE>()? != IHAVEOPT {
+                return Err(invalid_data("unexpected option magic"));
+            }
+            let option = reader.read_u32::<BE>()?;
+            let len = reader.read_u32::<BE>()?;
+            if len > MAX_OPTION_LEN {
+                return Err(invalid_data("option data is too long"));
+            }
+            // The export name is ignored. There is only one export.
+            let mut data = vec![0; len as usize];
+            reader.read_exact(&mut data)?;
+            debug!("nbd option {}", option);
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    writer.write_u64::<BE>(self.data.len() as u64)?;
+                    writer.write_u16::<BE>(self.transmission_flags())?;
+                    if client_flags & (NBD_FLAG_NO_ZEROES as u32) == 0 {
+                        writer.write_all(&[0; 124])?;
+                    }
+                    writer.flush()?;
+                    return Ok(true);
+                }
+                NBD_OPT_ABORT => {
+                    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    let mut info = Vec::new();
+                    info.write_u16::<BE>(NBD_INFO_EXPORT)?;
+                    info.write_u64::<BE>(self.data.len() as u64)?;
+                    info.write_u16::<BE>(self.transmission_flags())?;
+                    write_option_reply(writer, option, NBD_REP_INFO, &info)?;
+                    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                    if option == NBD_OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                _ => write_option_reply(writer, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        NBD_FLAG_HAS_FLAGS
+            | NBD_FLAG_SEND_FLUSH
+            | NBD_FLAG_SEND_FUA
+            | NBD_FLAG_SEND_TRIM
+            | NBD_FLAG_SEND_WRITE_ZEROES
+    }
+
+    /// Handle requests until the client disconnects.
+    fn transmission(&mut self, reader: &mut impl Read, writer: &mut impl Write) -> io::Result<()> {
+        loop {
+            let magic = match reader.read_u32::<BE>() {
+                Ok(magic) => magic,
+                // The client went away without NBD_CMD_DISC.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if magic != REQUEST_MAGIC {
+                return Err(invalid_data("unexpected request magic"));
+            }
+            let request = NbdRequest {
+                flags: reader.read_u16::<BE>()?,
+                kind: reader.read_u16::<BE>()?,
+                handle: reader.read_u64::<BE>()?,
+                offset: reader.read_u64::<BE>()?,
+                len: reader.read_u32::<BE>()?,
+            };
+            let mut payload = Vec::new();
+            if request.kind == NBD_CMD_WRITE {
+                if self.check_range(&request).is_ok() {
+                    payload.resize(request.len as usize, 0);
+                    reader.read_exact(&mut payload)?;
+                } else {
+                    // Skip the payload without allocating it. `handle` replies
+                    // with an error.
+                    let len = request.len as u64;
+                    if io::copy(&mut reader.take(len), &mut io::sink())? < len {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+            if request.kind == NBD_CMD_DISC {
+                return Ok(());
+            }
+            match self.handle(&request, payload) {
+                Ok(data) => {
+                    write_simple_reply(writer, request.handle, 0)?;
+                    writer.write_all(&data)?;
+                }
+                Err(errno) => write_simple_reply(writer, request.handle, errno as u32)?,
+            }
+            writer.flush()?;
+        }
+    }
+
+    /// Return the offset and end of a request within the image, or an errno.
+    fn check_range(&self, request: &NbdRequest) -> Result<(usize, usize), i32> {
+        let offset = usize::try_from(request.offset).map_err(|_| libc::EINVAL)?;
+        let end = offset
+            .checked_add(request.len as usize)
+            .ok_or(libc::EINVAL)?;
+        if end > self.data.len() {
+            return Err(match request.kind {
+                NBD_CMD_READ => libc::EINVAL,
+                _ => libc::ENOSPC,
+            });
+        }
+        Ok((offset, end))
+    }
+
+    /// Handle a request. Return data to send back, or an errno.
+    fn handle(&mut self, request: &NbdRequest, payload: Vec<u8>) -> Result<Vec<u8>, i32> {
+        let fua = request.flags & NBD_CMD_FLAG_FUA != 0;
+        match request.kind {
+            NBD_CMD_READ => {
+                let (offset, end) = self.check_range(request)?;
+                Ok(self.data.read(offset, end - offset))
+            }
+            NBD_CMD_WRITE | NBD_CMD_WRITE_ZEROES => {
+                let (offset, end) = self.check_range(request)?;
+                let data = if request.kind == NBD_CMD_WRITE {
+                    payload
+                } else {
+                    vec![0; end - offset]
+                };
+                self.data.write(offset, &data);
+                let change = if fua {
+                    Change::FuaWrite { offset, data }
+                } else {
+                    Change::Write { offset, data }
+                };
+                self.changes.push(change);
+                Ok(Vec::new())
+            }
+            NBD_CMD_FLUSH => {
+                // A flush persists the volatile cache, like fsync on the image.
+                if self.changes.last() != Some(&Change::Sync) {
+                    self.changes.push(Change::Sync);
+                }
+                Ok(Vec::new())
+            }
+            NBD_CMD_TRIM => {
+                let (offset, end) = self.check_range(request)?;
+                self.data
+                    .modify(offset..end, |buf, _| buf.iter_mut().for_each(|b| *b = 0));
+                let len = request.len as usize;
+                self.changes.push(Change::Discard { offset, len });
+                Ok(Vec::new())
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
+
+impl<'a> Drop for BackgroundServer<'a> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.stopped = true;
+            if let Some(stream) = state.stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        // Wake up the "accept" call.
+        let _ = UnixStream::connect(&self.socket_path);
+        if let Some(guard) = self.guard.take() {
+            if let Err(e) = guard.join() {
+                warn!("nbd server error: {}", e);
+            }
+        }
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+fn write_option_reply(
+    writer: &mut impl Write,
+    option: u32,
+    reply_type: u32,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_u64::<BE>(REPLY_MAGIC)?;
+    writer.write_u32::<BE>(option)?;
+    writer.write_u32::<BE>(reply_type)?;
+    writer.write_u32::<BE>(data.len() as u32)?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+fn write_simple_reply(writer: &mut impl Write, handle: u64, error: u32) -> io::Result<()> {
+    writer.write_u32::<BE>(SIMPLE_REPLY_MAGIC)?;
+    writer.write_u32::<BE>(error)?;
+    writer.write_u64::<BE>(handle)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn request(stream: &mut UnixStream, kind: u16, flags: u16, offset: u64, data: &[u8]) -> u32 {
+        stream.write_u32::<BE>(REQUEST_MAGIC).unwrap();
+        stream.write_u16::<BE>(flags).unwrap();
+        stream.write_u16::<BE>(kind).unwrap();
+        stream.write_u64::<BE>(42).unwrap();
+        stream.write_u64::<BE>(offset).unwrap();
+        stream.write_u32::<BE>(data.len() as u32).unwrap();
+        if kind == NBD_CMD_WRITE {
+            stream.write_all(data).unwrap();
+        }
+        assert_eq!(stream.read_u32::<BE>().unwrap(), SIMPLE_REPLY_MAGIC);
+        let error = stream.read_u32::<BE>().unwrap();
+        assert_eq!(stream.read_u64::<BE>().unwrap(), 42);
+        error
+    }
+
+    #[test]
+    fn test_nbd_server() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("sock");
+        let mut changes = Vec::new();
+        let server = NbdServer::new(vec![b'x'; 4], &mut changes);
+        let session = server.spawn(&socket_path).unwrap();
+
+        let mut stream = UnixStream::connect(&socket_path).unwrap();
+        assert_eq!(stream.read_u64::<BE>().unwrap(), NBDMAGIC);
+        assert_eq!(stream.read_u64::<BE>().unwrap(), IHAVEOPT);
+        stream.read_u16::<BE>().unwrap();
+        stream.write_u32::<BE>(NBD_FLAG_NO_ZEROES as u32).unwrap();
+        stream.write_u64::<BE>(IHAVEOPT).unwrap();
+        stream.write_u32::<BE>(NBD_OPT_EXPORT_NAME).unwrap();
+        stream.write_u32::<BE>(0).unwrap();
+        assert_eq!(stream.read_u64::<BE>().unwrap(), 4);
+        stream.read_u16::<BE>().unwrap();
+
+        assert_eq!(request(&mut stream, NBD_CMD_WRITE, 0, 1, b"ab"), 0);
+        assert_eq!(request(&mut stream, NBD_CMD_FLUSH, 0, 0, b""), 0);
+        assert_eq!(request(&mut stream, NBD_CMD_WRITE, 1, 0, b"c"), 0);
+        assert_eq!(request(&mut stream, NBD_CMD_TRIM, 0, 3, b"\0"), 0);
+        assert_eq!(request(&mut stream, NBD_CMD_WRITE, 0, 3, b"de"), 28);
+        // Ranges that overflow are rejected. The payload is skipped.
+        assert_eq!(request(&mut stream, NBD_CMD_WRITE, 0, u64::MAX, b"f"), 22);
+        assert_eq!(request(&mut stream, NBD_CMD_READ, 0, u64::MAX, b"f"), 22);
+        assert_eq!(request(&mut stream, NBD_CMD_TRIM, 0, u64::MAX, b"f"), 22);
+        assert_eq!(request(&mut stream, NBD_CMD_READ, 0, 0, b"1234"), 0);
+        let mut data = [0; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"cab\0");
+        drop(session);
+
+        assert_eq!(
+            changes,
+            vec![
+                Change::Write {
+                    offset: 1,
+                    data: b"ab".to_vec()
+                },
+                Change::Sync,
+                Change::FuaWrite {
+                    offset: 0,
+                    data: b"c".to_vec()
+                },
+                Change::Discard { offset: 3, len: 1 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Result of a test case in a report.
+#[derive(Clone, Debug)]
+pub struct CaseReport {
+    /// Name of the case. The filter of a generated test case.
+    pub name: String,
+
+    /// Group of the case. The strategy of a generated test case.
+    pub class: String,
+
+    pub outcome: Outcome,
+
+    /// Captured stdout of the verify script.
+    pub stdout: String,
+
+    /// Captured stderr of the verify script.
+    pub stderr: String,
+
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The case passed with the given exit code.
+    Pass(i32),
+
+    /// The case failed with the given exit code.
+    Fail(i32),
+
+    /// The case cannot be run.
+    Error(String),
+
+    /// The case was not run.
+    Skipped,
+}
+
+impl CaseReport {
+    /// Create a report of a case that was not run.
+    pub fn new(name: impl ToString, class: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            class: class.to_string(),
+            outcome: Outcome::Skipped,
+            stdout: String::new(),
+            stderr: String::new(),
+            duration: Duration::default(),
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        match self.outcome {
+            Outcome::Pass(code) | Outcome::Fail(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+/// Render reports as a JUnit XML test suite.
+pub fn to_junit(suite_name: &str, cases: &[CaseReport]) -> String {
+    let count = |f: fn(&Outcome) -> bool| cases.iter().filter(|c| f(&c.outcome)).count();
+    let time: Duration = cases.iter().map(|c| c.duration).sum();
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        out,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+        escape_xml(suite_name),
+        cases.len(),
+        count(|o| matches!(o, Outcome::Fail(_))),
+        count(|o| matches!(o, Outcome::Error(_))),
+        count(|o| matches!(o, Outcome::Skipped)),
+        time.as_secs_f64(),
+    );
+    for case in cases {
+        let _ = writeln!(
+            out,
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+            escape_xml(&case.name),
+            escape_xml(&case.class),
+            case.duration.as_secs_f64(),
+        );
+        if let Some(code) = case.exit_code() {
+            let _ = writeln!(
+                out,
+                "    <properties><property name=\"exit_code\" value=\"{}\"/></properties>",
+                code
+            );
+        }
+        match &case.outcome {
+            Outcome::Pass(_) => {}
+            Outcome::Fail(code) => {
+                let _ = writeln!(
+                    out,
+                    "    <failure message=\"verify script returned {}\"/>",
+                    code
+                );
+            }
+            Outcome::Error(message) => {
+                let _ = writeln!(out, "    <error message=\"{}\"/>", escape_xml(message));
+            }
+            Outcome::Skipped => out.push_str("    <skipped/>\n"),
+        }
+        if !case.stdout.is_empty() {
+            let _ = writeln!(
+                out,
+                "    <system-out>{}</system-out>",
+                escape_xml(&case.stdout)
+            );
+        }
+        if !case.stderr.is_empty() {
+            let _ = writeln!(
+                out,
+                "    <system-err>{}</system-err>",
+                escape_xml(&case.stderr)
+            );
+        }
+        out.push_str("  </testcase>\n");
+    }
+    out.push_str("</testsuite>\n");
+    out
+}
+
+/// Render reports in the Test Anything Protocol (TAP) version 13.
+pub fn to_tap(cases: &[CaseReport]) -> String {
+    let mut out = String::new();
+    out.push_str("TAP version 13\n");
+    let _ = writeln!(out, "1..{}", cases.len());
+    for (i, case) in cases.iter().enumerate() {
+        let ok = match case.outcome {
+            Outcome::Pass(_) | Outcome::Skipped => "ok",
+            Outcome::Fail(_) | Outcome::Error(_) => "not ok",
+        };
+        let _ = write!(out, "{} {} - {} ({})", ok, i + 1, case.name, case.class);
+        if case.outcome == Outcome::Skipped {
+            out.push_str(" # SKIP not run");
+        }
+        out.push('\n');
+        if case.outcome == Outcome::Skipped {
+            continue;
+        }
+        // YAML diagnostics.
+        out.push_str("  ---\n");
+        match &case.outcome {
+            Outcome::Error(message) => {
+                let _ = writeln!(out, "  error: {}", yaml_string(message));
+            }
+            _ => {
+                let _ = writeln!(out, "  exit_code: {}", case.exit_code().unwrap_or(0));
+            }
+        }
+        let _ = writeln!(out, "  duration_ms: {}", case.duration.as_millis());
+        for (name, text) in &[("stdout", &case.stdout), ("stderr", &case.stderr)] {
+            if !text.is_empty() {
+                let _ = writeln!(out, "  {}: |", name);
+                for line in text.lines() {
+                    let _ = writeln!(out, "    {}", line);
+                }
+            }
+        }
+        out.push_str("  ...\n");
+    }
+    out
+}
+
+/// Escape text for XML attributes and text nodes.
+///
+/// Control characters not allowed in XML are replaced.
+fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(ch),
+            c if (c as u32) < 0x20 => out.push('\u{fffd}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quote a single line string for YAML.
+fn yaml_string(text: &str) -> String {
+    format!("{:?}", text.replace('\n', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cases() -> Vec<CaseReport> {
+        let mut pass = CaseReport::new("0:01", "exhaustive");
+        pass.outcome = Outcome::Pass(11);
+        pass.stdout = "GOOD: old content\n".into();
+        pass.duration = Duration::from_millis(1500);
+        let mut fail = CaseReport::new("0:10", "exhaustive");
+        fail.outcome = Outcome::Fail(1);
+        fail.stderr = "BAD: <empty> & \"x\"\n".into();
+        let mut error = CaseReport::new("0:11", "reorder");
+        error.outcome = Outcome::Error("mount failed".into());
+        let skipped = CaseReport::new("1:1", "prefix");
+        vec![pass, fail, error, skipped]
+    }
+
+    #[test]
+    fn test_junit() {
+        assert_eq!(
+            to_junit("rename.py", &cases()),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuite name="rename.py" tests="4" failures="1" errors="1" skipped="1" time="1.500">
+  <testcase name="0:01" classname="exhaustive" time="1.500">
+    <properties><property name="exit_code" value="11"/></properties>
+    <system-out>GOOD: old content
+</system-out>
+  </testcase>
+  <testcase name="0:10" classname="exhaustive" time="0.000">
+    <properties><property name="exit_code" value="1"/></properties>
+    <failure message="verify script returned 1"/>
+    <system-err>BAD: &lt;empty&gt; &amp; &quot;x&quot;
+</system-err>
+  </testcase>
+  <testcase name="0:11" classname="reorder" time="0.000">
+    <error message="mount failed"/>
+  </testcase>
+  <testcase name="1:1" classname="prefix" time="0.000">
+    <skipped/>
+  </testcase>
+</testsuite>
+"#
+        );
+    }
+
+    #[test]
+    fn test_escape_xml() {
+        assert_eq!(escape_xml("a\u{1}\tb'"), "a\u{fffd}\tb&apos;");
+    }
+
+    #[test]
+    fn test_tap() {
+        assert_eq!(
+            to_tap(&cases()),
+            r#"TAP version 13
+1..4
+ok 1 - 0:01 (exhaustive)
+  ---
+  exit_code: 11
+  duration_ms: 1500
+  stdout: |
+    GOOD: old content
+  ...
+not ok 2 - 0:10 (exhaustive)
+  ---
+  exit_code: 1
+  duration_ms: 0
+  stderr: |
+    BAD: <empty> & "x"
+  ...
+not ok 3 - 0:11 (reorder)
+  ---
+  error: "mount failed"
+  duration_ms: 0
+  ...
+ok 4 - 1:1 (prefix) # SKIP not run
+"#
+        );
+    }
+}
@@ -0,0 +1,1117 @@
+use crate::errors::Context;
+use crate::journal::ChangeFilter;
+use crate::vendor::fuse;
+use crate::vendor::fuse::FileAttr;
+use crate::vendor::fuse::FileType;
+use crate::vendor::fuse::Filesystem;
+use crate::vendor::fuse::ReplyAttr;
+use crate::vendor::fuse::ReplyCreate;
+use crate::vendor::fuse::ReplyData;
+use crate::vendor::fuse::ReplyDirectory;
+use crate::vendor::fuse::ReplyEmpty;
+use crate::vendor::fuse::ReplyEntry;
+use crate::vendor::fuse::ReplyStatfs;
+use crate::vendor::fuse::ReplyWrite;
+use crate::vendor::fuse::Request;
+use log::debug;
+use log::info;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const ROOT_INO: u64 = 1;
+const TTL: Duration = Duration::from_secs(1);
+
+/// Represent a directory tree and a list of file-level changes to it.
+#[derive(Debug, Clone)]
+pub struct TreeJournal {
+    /// Initial tree.
+    pub initial_tree: Rc<Tree>,
+
+    /// Changes applied to the initial tree.
+    pub changes: Vec<TreeChange>,
+}
+
+/// A file-level change.
+///
+/// Files are referred by inode numbers. A new inode created by the `i`-th
+/// change uses inode number `first_new_ino + i`, so inode numbers stay the
+/// same when replaying a subset of changes.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TreeChange {
+    /// Create a regular file.
+    Create {
+        parent: u64,
+        name: String,
+        mode: u32,
+    },
+
+    /// Create a directory.
+    Mkdir {
+        parent: u64,
+        name: String,
+        mode: u32,
+    },
+
+    /// Create a symbolic link.
+    Symlink {
+        parent: u64,
+        name: String,
+        target: String,
+    },
+
+    /// Create a hard link.
+    Link { ino: u64, parent: u64, name: String },
+
+    /// Remove a file.
+    Unlink { parent: u64, name: String },
+
+    /// Remove an empty directory.
+    Rmdir { parent: u64, name: String },
+
+    /// Rename a file or directory, replacing the destination.
+    Rename {
+        parent: u64,
+        name: String,
+        new_parent: u64,
+        new_name: String,
+    },
+
+    /// Change the length of a file.
+    SetLen { ino: u64, len: usize },
+
+    /// Write to a file.
+    Write {
+        ino: u64,
+        offset: usize,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+
+    /// A "fsync" operation on a file.
+    Fsync { ino: u64 },
+
+    /// A "fsync" operation on a directory.
+    FsyncDir { ino: u64 },
+
+    /// Change permission bits of a file or directory.
+    Chmod { ino: u64, mode: u32 },
+}
+
+/// An in-memory directory tree.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    inodes: BTreeMap<u64, Inode>,
+
+    /// Inode number used by a new inode created by the first change.
+    first_new_ino: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    node: Node,
+    mode: u32,
+    nlink: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, u64>),
+    Symlink(String),
+}
+
+/// Crash consistency models to replay file-level changes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CrashModel {
+    /// Any change can be lost, unless a later "fsync" of the file, or
+    /// "fsync" of the parent directory is taken.
+    AnythingGoes,
+
+    /// Approximate ext4 with `data=ordered`.
+    ///
+    /// Metadata changes (including writes that extend a file) are persisted
+    /// in order. Overwrites can be lost. "fsync" commits all previous
+    /// metadata changes, and data of the file.
+    Ext4Ordered,
+}
+
+type Errno = libc::c_int;
+
+impl Tree {
+    /// Load a tree from a directory on disk.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut tree = Self {
+            inodes: BTreeMap::new(),
+            first_new_ino: ROOT_INO,
+        };
+        tree.load_dir(path)?;
+        tree.first_new_ino = tree.inodes.len() as u64 + ROOT_INO;
+        Ok(tree)
+    }
+
+    /// Load a directory recursively. Return its inode number.
+    fn load_dir(&mut self, path: &Path) -> io::Result<u64> {
+        let ino = self.inodes.len() as u64 + ROOT_INO;
+        let meta = fs::metadata(path).context(path.display())?;
+        self.inodes.insert(
+            ino,
+            Inode {
+                node: Node::Dir(BTreeMap::new()),
+                mode: meta.permissions().mode() & 0o7777,
+                nlink: 2,
+            },
+        );
+        let mut paths: Vec<_> = fs::read_dir(path)
+            .context(path.display())?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<_>>()?;
+        paths.sort();
+        let mut entries = BTreeMap::new();
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let meta = fs::symlink_metadata(&path).context(path.display())?;
+            let child_ino = if meta.is_dir() {
+                self.load_dir(&path)?
+            } else {
+                let node = if meta.file_type().is_symlink() {
+                    let target = fs::read_link(&path).context(path.display())?;
+                    Node::Symlink(target.to_string_lossy().to_string())
+                } else {
+                    Node::File(fs::read(&path).context(path.display())?)
+                };
+                let child_ino = self.inodes.len() as u64 + ROOT_INO;
+                let inode = Inode {
+                    node,
+                    mode: meta.permissions().mode() & 0o7777,
+                    nlink: 1,
+                };
+                self.inodes.insert(child_ino, inode);
+                child_ino
+            };
+            entries.insert(name, child_ino);
+        }
+        self.inodes.get_mut(&ino).unwrap().node = Node::Dir(entries);
+        Ok(ino)
+    }
+
+    /// Write the tree to a directory on disk.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.save_dir(ROOT_INO, path, &mut HashMap::new())
+    }
+
+    /// Write a directory recursively. `saved` tracks written files so
+    /// hard links are written as hard links.
+    fn save_dir(&self, ino: u64, path: &Path, saved: &mut HashMap<u64, PathBuf>) -> io::Result<()> {
+        fs::create_dir_all(path).context(path.display())?;
+        let entries = self.dir(ino).map_err(io::Error::from_raw_os_error)?;
+        for (name, &child_ino) in entries {
+            let child_path = path.join(name);
+            if let Some(saved_path) = saved.get(&child_ino) {
+                fs::hard_link(saved_path, &child_path).context(child_path.display())?;
+                continue;
+            }
+            let inode = &self.inodes[&child_ino];
+            match &inode.node {
+                Node::Dir(_) => self.save_dir(child_ino, &child_path, saved)?,
+                Node::File(data) => {
+                    fs::write(&child_path, data).context(child_path.display())?;
+                    let permissions = fs::Permissions::from_mode(inode.mode);
+                    fs::set_permissions(&child_path, permissions).context(child_path.display())?;
+                    saved.insert(child_ino, child_path);
+                }
+                Node::Symlink(target) => {
+                    std::os::unix::fs::symlink(target, &child_path).context(child_path.display())?
+                }
+            }
+        }
+        // Set permissions after writing entries, in case it is read-only.
+        let permissions = fs::Permissions::from_mode(self.inodes[&ino].mode);
+        fs::set_permissions(path, permissions).context(path.display())?;
+        Ok(())
+    }
+
+    fn dir(&self, ino: u64) -> Result<&BTreeMap<String, u64>, Errno> {
+        match self.inodes.get(&ino).map(|i| &i.node) {
+            Some(Node::Dir(entries)) => Ok(entries),
+            Some(_) => Err(libc::ENOTDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn dir_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, Errno> {
+        match self.inodes.get_mut(&ino).map(|i| &mut i.node) {
+            Some(Node::Dir(entries)) => Ok(entries),
+            Some(_) => Err(libc::ENOTDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn file_mut(&mut self, ino: u64) -> Result<&mut Vec<u8>, Errno> {
+        match self.inodes.get_mut(&ino).map(|i| &mut i.node) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Dir(_)) => Err(libc::EISDIR),
+            Some(_) => Err(libc::EINVAL),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn lookup(&self, parent: u64, name: &str) -> Result<u64, Errno> {
+        self.dir(parent)?.get(name).cloned().ok_or(libc::ENOENT)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.dir(ino).is_ok()
+    }
+
+    /// Test if `ino` is directory `dir` or in its subtree.
+    fn is_in_subtree(&self, dir: u64, ino: u64) -> bool {
+        dir == ino
+            || match self.dir(dir) {
+                Ok(entries) => entries
+                    .values()
+                    .any(|&child| self.is_dir(child) && self.is_in_subtree(child, ino)),
+                Err(_) => false,
+            }
+    }
+
+    /// Length of a file. Return 0 for non-files.
+    fn len(&self, ino: u64) -> usize {
+        match self.inodes.get(&ino).map(|i| &i.node) {
+            Some(Node::File(data)) => data.len(),
+            Some(Node::Symlink(target)) => target.len(),
+            _ => 0,
+        }
+    }
+
+    fn insert(&mut self, parent: u64, name: &str, ino: u64, inode: Inode) -> Result<(), Errno> {
+        if self.dir(parent)?.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+        self.inodes.insert(ino, inode);
+        self.dir_mut(parent)?.insert(name.to_string(), ino);
+        Ok(())
+    }
+
+    /// Remove a directory entry. Return the inode it pointed to.
+    fn remove(&mut self, parent: u64, name: &str) -> Result<u64, Errno> {
+        let ino = self.lookup(parent, name)?;
+        if let Some(Node::Dir(entries)) = self.inodes.get(&ino).map(|i| &i.node) {
+            if !entries.is_empty() {
+                return Err(libc::ENOTEMPTY);
+            }
+        }
+        self.dir_mut(parent)?.remove(name);
+        // Keep the inode so opened files can still be written.
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.nlink = inode.nlink.saturating_sub(1);
+        }
+        Ok(ino)
+    }
+
+    /// Apply the `index`-th change.
+    pub fn apply(&mut self, index: usize, change: &TreeChange) -> Result<(), Errno> {
+        let new_ino = self.first_new_ino + index as u64;
+        match change {
+            TreeChange::Create { parent, name, mode } => {
+                let inode = Inode {
+                    node: Node::File(Vec::new()),
+                    mode: *mode & 0o7777,
+                    nlink: 1,
+                };
+                self.insert(*parent, name, new_ino, inode)
+            }
+            TreeChange::Mkdir { parent, name, mode } => {
+                let inode = Inode {
+                    node: Node::Dir(BTreeMap::new()),
+                    mode: *mode & 0o7777,
+                    nlink: 2,
+                };
+                self.insert(*parent, name, new_ino, inode)
+            }
+            TreeChange::Symlink {
+                parent,
+                name,
+                target,
+            } => {
+                let inode = Inode {
+                    node: Node::Symlink(target.clone()),
+                    mode: 0o777,
+                    nlink: 1,
+                };
+                self.insert(*parent, name, new_ino, inode)
+            }
+            TreeChange::Link { ino, parent, name } => {
+                if self.is_dir(*ino) {
+                    return Err(libc::EPERM);
+                }
+                let mut inode = self.inodes.get(ino).cloned().ok_or(libc::ENOENT)?;
+                inode.nlink += 1;
+                self.insert(*parent, name, *ino, inode)
+            }
+            TreeChange::Unlink { parent, name } => {
+                if self.is_dir(self.lookup(*parent, name)?) {
+                    return Err(libc::EISDIR);
+                }
+                self.remove(*parent, name).map(|_| ())
+            }
+            TreeChange::Rmdir { parent, name } => {
+                if !self.is_dir(self.lookup(*parent, name)?) {
+                    return Err(libc::ENOTDIR);
+                }
+                self.remove(*parent, name).map(|_| ())
+            }
+            TreeChange::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+            } => {
+                let ino = self.lookup(*parent, name)?;
+                self.dir(*new_parent)?;
+                // Moving a directory into itself disconnects it from the tree.
+                if self.is_dir(ino) && self.is_in_subtree(ino, *new_parent) {
+                    return Err(libc::EINVAL);
+                }
+                if let Ok(old_ino) = self.lookup(*new_parent, new_name) {
+                    if old_ino == ino {
+                        return Ok(());
+                    }
+                    if self.is_dir(ino) != self.is_dir(old_ino) {
+                        return Err(if self.is_dir(ino) {
+                            libc::ENOTDIR
+                        } else {
+                            libc::EISDIR
+                        });
+                    }
+                    self.remove(*new_parent, new_name)?;
+                }
+                self.dir_mut(*parent)?.remove(name);
+                self.dir_mut(*new_parent)?.insert(new_name.clone(), ino);
+                Ok(())
+            }
+            TreeChange::SetLen { ino, len } => {
+                self.file_mut(*ino)?.resize(*len, 0);
+                Ok(())
+            }
+            TreeChange::Write { ino, offset, data } => {
+                let file = self.file_mut(*ino)?;
+                let end = offset + data.len();
+                if file.len() < end {
+                    file.resize(end, 0);
+                }
+                file[*offset..end].copy_from_slice(data);
+                Ok(())
+            }
+            TreeChange::Chmod { ino, mode } => {
+                let inode = self.inodes.get_mut(ino).ok_or(libc::ENOENT)?;
+                inode.mode = *mode & 0o7777;
+                Ok(())
+            }
+            TreeChange::Fsync { .. } | TreeChange::FsyncDir { .. } => Ok(()),
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let inode = self.inodes.get(&ino)?;
+        let kind = match inode.node {
+            Node::File(_) => FileType::RegularFile,
+            Node::Dir(_) => FileType::Directory,
+            Node::Symlink(_) => FileType::Symlink,
+        };
+        let size = self.len(ino) as u64;
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm: inode.mode as u16,
+            nlink: inode.nlink,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            flags: 0,
+        })
+    }
+}
+
+impl TreeChange {
+    /// Test if the change is a "sync"-like operation.
+    pub fn is_sync(&self) -> bool {
+        matches!(self, TreeChange::Fsync { .. } | TreeChange::FsyncDir { .. })
+    }
+
+    /// Test if the change modifies the namespace of the given directory.
+    fn modifies_dir(&self, dir: u64) -> bool {
+        match self {
+            TreeChange::Create { parent, .. }
+            | TreeChange::Mkdir { parent, .. }
+            | TreeChange::Symlink { parent, .. }
+            | TreeChange::Link { parent, .. }
+            | TreeChange::Unlink { parent, .. }
+            | TreeChange::Rmdir { parent, .. } => *parent == dir,
+            TreeChange::Rename {
+                parent, new_parent, ..
+            } => *parent == dir || *new_parent == dir,
+            _ => false,
+        }
+    }
+
+    /// Return the inode of which the content or permission is changed.
+    fn data_ino(&self) -> Option<u64> {
+        match self {
+            TreeChange::SetLen { ino, .. }
+            | TreeChange::Write { ino, .. }
+            | TreeChange::Chmod { ino, .. } => Some(*ino),
+            _ => None,
+        }
+    }
+}
+
+impl CrashModel {
+    pub fn name(self) -> &'static str {
+        match self {
+            CrashModel::AnythingGoes => "anything-goes",
+            CrashModel::Ext4Ordered => "ext4-ordered",
+        }
+    }
+}
+
+impl FromStr for CrashModel {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "anything-goes" => Ok(CrashModel::AnythingGoes),
+            "ext4-ordered" => Ok(CrashModel::Ext4Ordered),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown crash model: {}", s),
+            )),
+        }
+    }
+}
+
+impl TreeJournal {
+    /// Load state. `base_path` is a directory.
+    pub fn load(base_path: &Path, changes_path: &Path) -> io::Result<Self> {
+        let tree = Tree::load(base_path)?;
+        let changes: Vec<TreeChange> = if changes_path.exists() {
+            let data = fs::read(changes_path)?;
+            varbincode::deserialize(&data[..])
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid changes data"))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            initial_tree: Rc::new(tree),
+            changes,
+        })
+    }
+
+    /// Dump changes. The base directory is not modified.
+    pub fn dump(&self, changes_path: &Path) -> io::Result<()> {
+        if !self.changes.is_empty() || changes_path.exists() {
+            fs::write(changes_path, varbincode::serialize(&self.changes).unwrap())
+                .context(changes_path.display())?;
+        }
+        Ok(())
+    }
+
+    /// Return the tree with changes applied.
+    ///
+    /// Changes that cannot be applied, for example, writing to a file whose
+    /// creation is skipped, are ignored.
+    pub fn tree(&self, filter: Option<&ChangeFilter>) -> Tree {
+        let mut tree = Tree::clone(&self.initial_tree);
+        let order = match filter {
+            Some(filter) => filter.replay_order(self.changes.len()),
+            None => (0..self.changes.len()).collect(),
+        };
+        for i in order {
+            let change = &self.changes[i];
+            if let Err(e) = tree.apply(i, change) {
+                debug!("skipped change {} ({:?}): errno {}", i, change, e);
+            }
+        }
+        tree
+    }
+
+    /// Mount to the destination directory.
+    ///
+    /// File-level changes are recorded in this journal.
+    ///
+    /// When the returned value gets dropped, umount the filesystem.
+    pub fn mount(
+        &mut self,
+        dest: &Path,
+        opts: &[String],
+        filter: Option<&ChangeFilter>,
+    ) -> io::Result<fuse::BackgroundSession> {
+        let tree = self.tree(filter);
+        let fs = FuseTreeFilesystem {
+            tree,
+            changes: &mut self.changes,
+        };
+        let opts: Vec<&OsStr> = opts.iter().map(OsStr::new).collect();
+        debug!("fuse mount options: {:?}", &opts);
+        unsafe { fuse::spawn_mount(fs, dest, &opts) }
+    }
+
+    /// Generate filters for testing under the given crash consistency model,
+    /// with the strategy ("exhaustive" or "random") that produced each.
+    ///
+    /// `seed` is used for random sampling.
+    pub fn gen_tests(
+        &self,
+        model: CrashModel,
+        max_cases_log2: usize,
+        seed: u64,
+    ) -> Vec<(String, &'static str)> {
+        let changes = &self.changes;
+
+        // Figure out writes that extend files. They change metadata (size).
+        let mut tree = Tree::clone(&self.initial_tree);
+        let mut is_metadata = Vec::with_capacity(changes.len());
+        for (i, change) in changes.iter().enumerate() {
+            let metadata = match change {
+                TreeChange::Write { ino, offset, data } => offset + data.len() > tree.len(*ino),
+                TreeChange::Fsync { .. } | TreeChange::FsyncDir { .. } => false,
+                _ => true,
+            };
+            is_metadata.push(metadata);
+            let _ = tree.apply(i, change);
+        }
+
+        let mut crash_points: Vec<usize> = (0..changes.len())
+            .filter(|&i| changes[i].is_sync())
+            .collect();
+        if changes.last().map(|c| !c.is_sync()) == Some(true) {
+            crash_points.push(changes.len());
+        }
+
+        let mut result = Vec::new();
+        let mut rng = StdRng::seed_from_u64(seed);
+        for end in crash_points {
+            // "ordered": changes that are lost as a suffix.
+            // "free": changes that are lost independently.
+            let mut ordered = Vec::new();
+            let mut free = Vec::new();
+            for i in 0..end {
+                let change = &changes[i];
+                if change.is_sync() {
+                    continue;
+                }
+                // Is the change persisted by a later "fsync"?
+                let synced = changes[i + 1..end].iter().any(|c| match c {
+                    TreeChange::Fsync { ino } => {
+                        change.data_ino() == Some(*ino)
+                            || (model == CrashModel::Ext4Ordered && is_metadata[i])
+                    }
+                    TreeChange::FsyncDir { ino } => {
+                        change.modifies_dir(*ino)
+                            || (model == CrashModel::Ext4Ordered && is_metadata[i])
+                    }
+                    _ => false,
+                });
+                if synced {
+                    continue;
+                }
+                if model == CrashModel::Ext4Ordered && is_metadata[i] {
+                    ordered.push(i);
+                } else {
+                    free.push(i);
+                }
+            }
+            let start = ordered
+                .first()
+                .cloned()
+                .unwrap_or(end)
+                .min(free.first().cloned().unwrap_or(end));
+            let to_filter = |ordered_count: usize, bits: &[bool]| -> String {
+                let mut bits_str = vec!["1"; end - start];
+                for &i in &ordered[ordered_count..] {
+                    bits_str[i - start] = "0";
+                }
+                for (&i, &b) in free.iter().zip(bits) {
+                    bits_str[i - start] = if b { "1" } else { "0" };
+                }
+                format!("{}:{}", start, bits_str.concat())
+            };
+
+            let width = free.len();
+            if width == 0 && ordered.is_empty() {
+                continue;
+            }
+            let total_log2 = width + ((ordered.len() + 1) as f64).log2().ceil() as usize;
+            if total_log2 <= max_cases_log2 {
+                info!(
+                    "# All cases for {} ordered and {} unordered changes before #{}",
+                    ordered.len(),
+                    width,
+                    end
+                );
+                for ordered_count in 0..=ordered.len() {
+                    for n in 0..(1usize << width) {
+                        let bits: Vec<bool> = (0..width)
+                            .map(|k| (n >> (width - 1 - k)) & 1 == 1)
+                            .collect();
+                        result.push((to_filter(ordered_count, &bits), "exhaustive"));
+                    }
+                }
+            } else {
+                let n = 1 << max_cases_log2;
+                info!(
+                    "# Random {} cases for {} ordered and {} unordered changes before #{}",
+                    n,
+                    ordered.len(),
+                    width,
+                    end
+                );
+                let mut visited: HashSet<String> = HashSet::new();
+                while visited.len() < n {
+                    let ordered_count = rng.gen_range(0, ordered.len() + 1);
+                    let bits: Vec<bool> = (0..width).map(|_| rng.gen()).collect();
+                    let filter = to_filter(ordered_count, &bits);
+                    if visited.insert(filter.clone()) {
+                        result.push((filter, "random"));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Fuse state for the "posix" mode - a directory tree recording file-level
+/// changes.
+pub struct FuseTreeFilesystem<'a> {
+    tree: Tree,
+
+    /// Modifications to the tree.
+    changes: &'a mut Vec<TreeChange>,
+}
+
+impl<'a> FuseTreeFilesystem<'a> {
+    /// Apply and record a change. Return the inode number used by a new inode.
+    fn record(&mut self, change: TreeChange) -> Result<u64, Errno> {
+        let index = self.changes.len();
+        self.tree.apply(index, &change)?;
+        self.changes.push(change);
+        Ok(self.tree.first_new_ino + index as u64)
+    }
+
+    fn reply_entry(&self, ino: Result<u64, Errno>, reply: ReplyEntry) {
+        match ino.and_then(|ino| self.tree.attr(ino).ok_or(libc::ENOENT)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn reply_empty(result: Result<u64, Errno>, reply: ReplyEmpty) {
+        match result {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+fn name_str(name: &OsStr) -> String {
+    name.to_string_lossy().to_string()
+}
+
+impl<'a> Filesystem for FuseTreeFilesystem<'a> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = self.tree.lookup(parent, &name_str(name));
+        self.reply_entry(ino, reply);
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.tree.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Files are always owned by the current user. Timestamps are not
+        // tracked, so changing them has no effect.
+        let owner = unsafe { (libc::getuid(), libc::getgid()) };
+        if uid.unwrap_or(owner.0) != owner.0 || gid.unwrap_or(owner.1) != owner.1 {
+            return reply.error(libc::EPERM);
+        }
+        if let Some(mode) = mode {
+            if let Err(e) = self.record(TreeChange::Chmod { ino, mode }) {
+                return reply.error(e);
+            }
+        }
+        if let Some(size) = size {
+            let len = size as usize;
+            if let Err(e) = self.record(TreeChange::SetLen { ino, len }) {
+                return reply.error(e);
+            }
+        }
+        match self.tree.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.tree.inodes.get(&ino).map(|i| &i.node) {
+            Some(Node::Symlink(target)) => reply.data(target.as_bytes()),
+            _ => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let name = name_str(name);
+        let ino = self.record(TreeChange::Mkdir { parent, name, mode });
+        self.reply_entry(ino, reply);
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name_str(name);
+        Self::reply_empty(self.record(TreeChange::Unlink { parent, name }), reply);
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name_str(name);
+        Self::reply_empty(self.record(TreeChange::Rmdir { parent, name }), reply);
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name = name_str(name);
+        let target = link.to_string_lossy().to_string();
+        let ino = self.record(TreeChange::Symlink {
+            parent,
+            name,
+            target,
+        });
+        self.reply_entry(ino, reply);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let change = TreeChange::Rename {
+            parent,
+            name: name_str(name),
+            new_parent,
+            new_name: name_str(new_name),
+        };
+        Self::reply_empty(self.record(change), reply);
+    }
+
+    fn link(&mut self, _req: &Request, ino: u64, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name_str(name);
+        let result = self.record(TreeChange::Link { ino, parent, name });
+        self.reply_entry(result.map(|_| ino), reply);
+    }
+
+    fn read(&mut self, _: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        match self.tree.inodes.get(&ino).map(|i| &i.node) {
+            Some(Node::File(data)) => {
+                let end = (offset as usize + size as usize).min(data.len());
+                let offset = (offset as usize).min(end);
+                reply.data(&data[offset..end]);
+            }
+            Some(Node::Dir(_)) => reply.error(libc::EISDIR),
+            _ => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let change = TreeChange::Write {
+            ino,
+            offset: offset as usize,
+            data: data.to_vec(),
+        };
+        match self.record(change) {
+            Ok(_) => reply.written(data.len() as u32),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        Self::reply_empty(self.record(TreeChange::Fsync { ino }), reply);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.tree.dir(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        let mut items = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ino, FileType::Directory, "..".to_string()),
+        ];
+        for (name, &child) in entries {
+            if let Some(attr) = self.tree.attr(child) {
+                items.push((child, attr.kind, name.clone()));
+            }
+        }
+        for (i, (ino, kind, name)) in items.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        Self::reply_empty(self.record(TreeChange::FsyncDir { ino }), reply);
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let blocks: usize = self.tree.inodes.keys().map(|&i| self.tree.len(i)).sum();
+        reply.statfs((blocks / 512) as _, 0, 0, 0, 0, 512, 255, 0);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let name = name_str(name);
+        let attr = self
+            .record(TreeChange::Create { parent, name, mode })
+            .and_then(|ino| self.tree.attr(ino).ok_or(libc::ENOENT));
+        match attr {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn journal() -> TreeJournal {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"old").unwrap();
+        let tree = Tree::load(dir.path()).unwrap();
+        TreeJournal {
+            initial_tree: Rc::new(tree),
+            changes: Vec::new(),
+        }
+    }
+
+    fn read(tree: &Tree, name: &str) -> Option<Vec<u8>> {
+        let ino = tree.lookup(ROOT_INO, name).ok()?;
+        match &tree.inodes[&ino].node {
+            Node::File(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_tree_save() {
+        let mut journal = journal();
+        let new_ino = journal.initial_tree.first_new_ino;
+        let name = |s: &str| s.to_string();
+        journal.changes = vec![
+            TreeChange::Mkdir {
+                parent: ROOT_INO,
+                name: name("d"),
+                mode: 0o755,
+            },
+            TreeChange::Link {
+                ino: ROOT_INO + 1,
+                parent: new_ino,
+                name: name("b"),
+            },
+            TreeChange::Symlink {
+                parent: new_ino,
+                name: name("c"),
+                target: name("b"),
+            },
+        ];
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out");
+        journal.tree(None).save(&path).unwrap();
+        assert_eq!(fs::read(path.join("a")).unwrap(), b"old");
+        assert_eq!(fs::read(path.join("d/c")).unwrap(), b"old");
+        let meta = fs::metadata(path.join("d/b")).unwrap();
+        assert_eq!(std::os::unix::fs::MetadataExt::nlink(&meta), 2);
+
+        let tree = Tree::load(&path).unwrap();
+        assert_eq!(read(&tree, "a"), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_tree_changes() {
+        let mut journal = journal();
+        let new_ino = journal.initial_tree.first_new_ino;
+        let name = |s: &str| s.to_string();
+        journal.changes = vec![
+            TreeChange::Create {
+                parent: ROOT_INO,
+                name: name("b"),
+                mode: 0o644,
+            },
+            TreeChange::Write {
+                ino: new_ino,
+                offset: 0,
+                data: b"new".to_vec(),
+            },
+            TreeChange::Rename {
+                parent: ROOT_INO,
+                name: name("b"),
+                new_parent: ROOT_INO,
+                new_name: name("a"),
+            },
+        ];
+        let tree = journal.tree(None);
+        assert_eq!(read(&tree, "a"), Some(b"new".to_vec()));
+        assert_eq!(read(&tree, "b"), None);
+
+        // Skip the write. "a" becomes empty.
+        let filter: ChangeFilter = "101".parse().unwrap();
+        let tree = journal.tree(Some(&filter));
+        assert_eq!(read(&tree, "a"), Some(Vec::new()));
+
+        // Skip the create. Other changes fail to apply.
+        let filter: ChangeFilter = "011".parse().unwrap();
+        let tree = journal.tree(Some(&filter));
+        assert_eq!(read(&tree, "a"), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_tree_chmod_rename_dir() {
+        let mut tree = Tree::clone(&journal().initial_tree);
+        let a = tree.lookup(ROOT_INO, "a").unwrap();
+        let chmod = TreeChange::Chmod {
+            ino: a,
+            mode: 0o100600,
+        };
+        tree.apply(0, &chmod).unwrap();
+        assert_eq!(tree.attr(a).unwrap().perm, 0o600);
+
+        // Moving a directory into its subtree fails.
+        let mkdir = |parent: u64, name: &str| TreeChange::Mkdir {
+            parent,
+            name: name.to_string(),
+            mode: 0o755,
+        };
+        let d = tree.first_new_ino + 1;
+        tree.apply(1, &mkdir(ROOT_INO, "d")).unwrap();
+        tree.apply(2, &mkdir(d, "e")).unwrap();
+        let rename = |new_parent: u64| TreeChange::Rename {
+            parent: ROOT_INO,
+            name: "d".to_string(),
+            new_parent,
+            new_name: "f".to_string(),
+        };
+        assert_eq!(tree.apply(3, &rename(d)), Err(libc::EINVAL));
+        assert_eq!(tree.apply(3, &rename(d + 1)), Err(libc::EINVAL));
+        assert_eq!(tree.apply(3, &rename(ROOT_INO)), Ok(()));
+        assert_eq!(tree.lookup(ROOT_INO, "f"), Ok(d));
+    }
+
+    #[test]
+    fn test_tree_gen_tests() {
+        let mut journal = journal();
+        let new_ino = journal.initial_tree.first_new_ino;
+        journal.changes = vec![
+            TreeChange::Create {
+                parent: ROOT_INO,
+                name: "b".to_string(),
+                mode: 0o644,
+            },
+            TreeChange::Write {
+                ino: new_ino,
+                offset: 0,
+                data: b"new".to_vec(),
+            },
+            TreeChange::Fsync { ino: new_ino },
+            TreeChange::Rename {
+                parent: ROOT_INO,
+                name: "b".to_string(),
+                new_parent: ROOT_INO,
+                new_name: "a".to_string(),
+            },
+        ];
+        let tests = journal.gen_tests(CrashModel::Ext4Ordered, 8, 0);
+        let filters: Vec<&str> = tests.iter().map(|(filter, _)| filter.as_str()).collect();
+        assert_eq!(filters, vec!["0:00", "0:10", "0:11", "3:0", "3:1"]);
+        assert!(tests.iter().all(|(_, strategy)| *strategy == "exhaustive"));
+        let tests = journal.gen_tests(CrashModel::AnythingGoes, 8, 0);
+        assert_eq!(tests.len(), 4 + 4);
+    }
+}